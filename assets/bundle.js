@@ -15,6 +15,7 @@ var ground = Chessground(document.getElementById('chessboard'), {
         enabled: false
     },
     events: {
+        move: count_halfmove,
         change: update
     }
 });
@@ -27,6 +28,7 @@ var fen = $('#fen');
 var move = $('#move');
 var move_checkbox = $('#move :checkbox');
 var mate = $('#mate');
+var clock = $('#clock');
 
 
 set_target_field('g1');
@@ -53,10 +55,19 @@ fen.keyup(function (event) {
         ground.set({
             fen: fen.val()
         });
+        // the halfmove clock is the fifth field of a full FEN
+        var fields = fen.val().trim().split(/\s+/);
+        if (fields.length >= 5)
+            clock.val(fields[4]);
         update()
     }
 });
 
+clock.keyup(function (event) {
+    if (event.keyCode === 13)
+        update();
+});
+
 target.keyup(function (event) {
     if (event.keyCode === 13) {
         if (target.val().length === 2 && target.val()[0] >= 'a' && target.val()[0] <= 'h' && target.val()[1] >= '1' && target.val()[1] <= '8')
@@ -66,6 +77,11 @@ target.keyup(function (event) {
 
 
 
+// there are no pawns, so only a capture resets the clock
+function count_halfmove(orig, dest, captured) {
+    clock.val(captured ? 0 : (parseInt(clock.val(), 10) || 0) + 1);
+}
+
 function set_target_field(key) {
     target_field = key;
     update();
@@ -84,13 +100,13 @@ function evaluate() {
         type: "POST",
         url: "/eval",
         contentType: "application/json",
-        data: JSON.stringify({ fen: ground.getFen() + ' ' + ground.state.turnColor[0] + ' ---- - 0 1', target: target_field }),
+        data: JSON.stringify({ fen: ground.getFen() + ' ' + ground.state.turnColor[0] + ' ---- - ' + (parseInt(clock.val(), 10) || 0) + ' 1', target: target_field }),
         success: function (response) {
             if (response.mate_in < 0) {
-                mate.text('DRAW')
+                mate.text(response.unknown ? 'UNKNOWN' : response.win ? 'WIN' : 'DRAW')
             }
             else {
-                mate.text(Math.trunc(response.mate_in / 2) + ' (' + response.mate_in + ')')
+                mate.text(Math.trunc(response.mate_in / 2) + ' (' + response.mate_in + ')' + (response.in_time === false ? ' may come too late for the 50-move rule' : ''))
             }
             console.log(response);
             var shapes = response.best_moves.map(function (x) {
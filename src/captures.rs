@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use crate::encoding::Mode;
+use crate::material::Material;
+use crate::search::{GenOptions, WIDE_DRAW};
+use crate::state::State;
+use crate::tablebase::Tablebase;
+use crate::target::Target;
+use chess::Board;
+
+// The tablebases of the materials that are left after black captured a piece, generated with the capture mode of the table.
+// They keep the captures of their own generation in turn, without any pieces white can't mate anymore.
+pub struct Captures {
+    tables: Vec<(Material, Tablebase)>,
+}
+
+// Where the table of a material after a capture is stored, next to the file of the tablebase with its extension or in the
+// directory of a partitioned one. The tables of all captures are stored there, also the ones after several captures.
+pub fn capture_path(base: &Path, material: Material) -> PathBuf {
+    if base.is_dir() {
+        base.join(format!("{}.tb", material))
+    }
+    else {
+        let stem = base.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        let extension = base.extension().map_or(String::from("tb"), |extension| extension.to_string_lossy().into_owned());
+        base.with_file_name(format!("{}.{}.{}", stem, material, extension))
+    }
+}
+
+impl Captures {
+    pub fn generate(threads: usize, material: Material, mode: Mode) -> Result<Self, String> {
+        let mut tables = vec![];
+        for captured in material.capture_tables() {
+            println!("Generating {} for the positions after a capture...", captured);
+            let mut tb = Tablebase::generate(threads, captured, mode.capture_mode(), GenOptions { wide: true, ..GenOptions::default() })?;
+            tb.normalize();
+            tables.push((captured, tb));
+        }
+        Ok(Captures { tables })
+    }
+
+    // Maps the tables of the given materials that are stored next to the tablebase at base, see capture_path.
+    // They have to be complete and have every target of the mode, gen writes them together with the tablebase.
+    // Without distances they are the win/draw bitmaps that wdl writes next to the bitmap of the tablebase.
+    pub fn open(base: &Path, materials: &[Material], mode: Mode, distances: bool) -> Result<Self, String> {
+        let mut tables = vec![];
+        for captured in materials {
+            let path = capture_path(base, *captured);
+            let command = if distances { "gen" } else { "wdl" };
+            let file = File::open(&path).map_err(|e| format!("The {} tablebase for the positions after a capture is missing, {} writes it to {}: {}", captured, command, path.display(), e))?;
+            let tb = Tablebase::map_from_disk(file).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if tb.material() != *captured || tb.max_depth() != 0 {
+                return Err(format!("{} is not the complete {} tablebase that is needed after a capture!", path.display(), captured));
+            }
+            if tb.has_distances() != distances {
+                let kind = if distances { "distances to mate" } else { "a win/draw bitmap" };
+                return Err(format!("{} has to be {} like the tablebase it belongs to!", path.display(), kind));
+            }
+            if !tb.mode().covers(mode) {
+                return Err(format!("{} was generated for {:?}, but the positions after a capture need {:?}!", path.display(), tb.mode(), mode));
+            }
+            println!("Mapped {} for the positions after a capture", path.display());
+            let mut tb = tb.with_captures(Captures::open(base, &captured.capture_tables(), mode, distances)?);
+            tb.normalize();
+            tables.push((*captured, tb));
+        }
+        Ok(Captures { tables })
+    }
+
+    // writes the table with the ones after its captures
+    fn write_table(base: &Path, material: Material, tb: &Tablebase) -> Result<(), String> {
+        let path = capture_path(base, material);
+        tb.write_to_disk(File::create(&path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?);
+        tb.write_captures_to_disk(base)
+    }
+
+    // writes every table next to the tablebase at base, see capture_path
+    pub fn write(&self, base: &Path) -> Result<(), String> {
+        for (material, tb) in &self.tables {
+            Captures::write_table(base, *material, tb)?;
+        }
+        Ok(())
+    }
+
+    // writes the win/draw bitmap of every table next to the bitmap at base, see capture_path
+    pub fn write_wdl(&self, base: &Path) -> Result<(), String> {
+        for (material, tb) in &self.tables {
+            let path = capture_path(base, *material);
+            tb.write_wdl_to_disk(File::create(&path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?);
+            tb.write_wdl_captures_to_disk(base)?;
+        }
+        Ok(())
+    }
+
+    // whether white can force mate after a capture, which is all the tables of a win/draw bitmap know
+    pub fn is_win(&self, state: &State) -> Result<bool, String> {
+        match self.tables.iter().find(|(material, _)| *material == state.material) {
+            Some((_, tb)) => tb.is_win(tb.index_of(state)),
+            None => {
+                assert_eq!(state.material.count(), 0);
+                Ok(false)
+            }
+        }
+    }
+
+    // the distance to mate of a position after a capture, with the sentinels of wide tables
+    pub fn get(&self, state: &State) -> u16 {
+        match self.tables.iter().find(|(material, _)| *material == state.material) {
+            Some((_, tb)) => tb.get(tb.index_of(state)),
+            None => {
+                assert_eq!(state.material.count(), 0);
+                WIDE_DRAW
+            }
+        }
+    }
+
+    // like get, but black may have taken more pieces, these are looked up in the captures of the tables
+    pub fn value_of(&self, board: &Board, target: Target) -> Result<u16, String> {
+        let state = State::from_board(*board, target)?;
+        if state.material.count() == 0 {
+            return Ok(WIDE_DRAW);
+        }
+        match self.tables.iter().find(|(material, _)| material.contains(state.material)) {
+            Some((_, tb)) => tb.value_of(board, target),
+            None => Ok(WIDE_DRAW),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Position;
+
+    // a table with only some target fields gets the tables after a capture with all of them
+    #[test]
+    fn captures_of_some_fields_have_every_field() {
+        let material = Material::from_string("KRRvK").unwrap();
+        let captures = Captures::generate(1, material, Mode::from_fields(&[Position::from_u8(0)])).unwrap();
+        assert_eq!(captures.tables.len(), 1);
+        let (captured, tb) = &captures.tables[0];
+        assert_eq!(*captured, Material::from_string("KRvK").unwrap());
+        assert_eq!(tb.mode(), Mode::Target);
+    }
+
+    // the stored tables are only mapped, a missing one or one without the targets that are needed is an error
+    #[test]
+    fn stored_tables_have_to_cover_the_mode() {
+        let directory = std::env::temp_dir().join(format!("3n2k_captures_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let material = Material::from_string("KRRvK").unwrap();
+        let captured = material.capture_tables();
+        let missing = Captures::open(&directory, &captured, Mode::Target, true).err().unwrap();
+        assert!(missing.contains("KRvK") && missing.contains("missing"), "{}", missing);
+        Captures::generate(1, material, Mode::Target).unwrap().write(&directory).unwrap();
+        assert!(Captures::open(&directory, &captured, Mode::Target, true).is_ok());
+        assert!(Captures::open(&directory, &captured, Mode::from_fields(&[Position::from_u8(0)]), true).is_ok());
+        assert!(Captures::open(&directory, &captured, Mode::Anywhere, true).is_err());
+        assert!(Captures::open(&directory, &captured, Mode::Target, false).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
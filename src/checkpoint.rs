@@ -0,0 +1,163 @@
+use std::fs::{File, rename};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, AtomicU16};
+use indicatif::ProgressBar;
+use crate::encoding::{IndexScheme, Mode};
+use crate::material::Material;
+use crate::search::{Dp, DtmCell, DpCells, rebuild_counters};
+
+pub const CHECKPOINT_MAGIC: [u8; 8] = *b"3N2KCKP\n";
+pub const CHECKPOINT_VERSION: u16 = 1;
+
+// The state of retrograde_search between two layers. The positions found in the last layer are not stored,
+// they are exactly the ones with dp == layer - 1. The dp array keeps the counters of the black positions, see Dp.
+pub struct Checkpoint {
+    pub material: Material,
+    pub mode: Mode,
+    pub dp: DpCells,
+    // the next layer to calculate
+    pub layer: u16,
+    pub white_to_play: bool,
+}
+
+fn write_atomics<W: Write, F>(writer: &mut W, len: usize, f: F) where F: Fn(usize) -> u8 {
+    let bar = ProgressBar::new(len as u64);
+    let mut buffer = Vec::with_capacity(2000000);
+    for start in (0..len).step_by(2000000) {
+        let end = if start + 2000000 > len { len } else { start + 2000000 };
+        buffer.clear();
+        buffer.extend((start..end).map(&f));
+        writer.write_all(&buffer).unwrap();
+        bar.inc((end - start) as u64);
+    }
+    bar.finish();
+}
+
+fn read_atomics<R: Read, T, F>(reader: &mut R, len: usize, f: F) -> Result<Vec<T>, String> where F: Fn(u8) -> T {
+    let bar = ProgressBar::new(len as u64);
+    let mut result = Vec::with_capacity(len);
+    let mut buffer = vec![0u8; 2000000];
+    while result.len() < len {
+        let size = if len - result.len() < buffer.len() { len - result.len() } else { buffer.len() };
+        reader.read_exact(&mut buffer[..size]).map_err(|e| format!("Checkpoint is truncated: {}", e))?;
+        result.extend(buffer[..size].iter().map(|b| f(*b)));
+        bar.inc(size as u64);
+    }
+    bar.finish();
+    Ok(result)
+}
+
+pub fn write_checkpoint<C: DtmCell>(path: &Path, material: Material, mode: Mode, dp: &Dp<C>, layer: u16, white_to_play: bool) {
+    println!("Writing checkpoint for layer {}...", layer);
+    // write to a temporary file first, so a crash while writing doesn't destroy the previous checkpoint
+    let tmp = path.with_extension("tmp");
+    {
+        let file = File::create(&tmp).unwrap();
+        let mut writer = BufWriter::new(&file);
+        writer.write_all(&CHECKPOINT_MAGIC).unwrap();
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes()).unwrap();
+        writer.write_all(&[material.to_u8(), mode.to_u8()]).unwrap();
+        writer.write_all(&mode.region().to_le_bytes()).unwrap();
+        writer.write_all(&[C::WIDTH as u8, dp.scheme().to_u8()]).unwrap();
+        writer.write_all(&layer.to_le_bytes()).unwrap();
+        writer.write_all(&[white_to_play as u8]).unwrap();
+        writer.write_all(&(dp.len() as u64).to_le_bytes()).unwrap();
+        write_atomics(&mut writer, dp.len() * C::WIDTH, |i| (dp.raw(i / C::WIDTH) >> (8 * (i % C::WIDTH))) as u8);
+        writer.flush().unwrap();
+        file.sync_all().unwrap();
+    }
+    rename(&tmp, path).unwrap();
+}
+
+impl Checkpoint {
+    // continues a table that was generated with --max-depth, as if the search had written a checkpoint after its last layer
+    pub fn from_table(dp: &[u8], width: usize, material: Material, mode: Mode, scheme: IndexScheme, max_depth: u16) -> Self {
+        println!("Restoring the search after layer {}...", max_depth);
+        let dp = if width == 2 {
+            let dp = Dp::new(dp.chunks(2).map(|b| AtomicU16::new(u16::from_le_bytes([b[0], b[1]]))).collect(), material, mode, scheme);
+            rebuild_counters(&dp, mode, max_depth);
+            DpCells::Wide(dp)
+        }
+        else {
+            let dp = Dp::new(dp.iter().map(|b| AtomicU8::new(*b)).collect(), material, mode, scheme);
+            rebuild_counters(&dp, mode, max_depth);
+            DpCells::Narrow(dp)
+        };
+        let layer = max_depth + 1;
+        Checkpoint { material, mode, dp, layer, white_to_play: layer % 2 == 0 }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        println!("Reading checkpoint...");
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(&file);
+        let mut buf = [0u8; 8 + 2 + 1 + 1 + 8 + 1 + 1 + 2 + 1 + 8];
+        reader.read_exact(&mut buf).map_err(|e| format!("Checkpoint is truncated: {}", e))?;
+        if buf[..8] != CHECKPOINT_MAGIC {
+            return Err(format!("{} is not a checkpoint!", path.display()));
+        }
+        let version = u16::from_le_bytes([buf[8], buf[9]]);
+        if version != CHECKPOINT_VERSION {
+            return Err(format!("Checkpoint has version {}, but only version {} is supported!", version, CHECKPOINT_VERSION));
+        }
+        let material = Material::from_u8(buf[10]).ok_or_else(|| format!("Checkpoint uses the unknown material {}!", buf[10]))?;
+        let mut region = [0u8; 8];
+        region.copy_from_slice(&buf[12..20]);
+        let mode = Mode::from_u8(buf[11], u64::from_le_bytes(region)).ok_or_else(|| format!("Checkpoint uses the unknown mode {}!", buf[11]))?;
+        let width = buf[20];
+        let scheme = IndexScheme::from_u8(buf[21]).ok_or_else(|| format!("Checkpoint uses the unknown index scheme {}!", buf[21]))?;
+        let layer = u16::from_le_bytes([buf[22], buf[23]]);
+        let white_to_play = buf[24] == 1;
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&buf[25..]);
+        let state_count = mode.state_count(material);
+        if u64::from_le_bytes(len) != state_count as u64 {
+            return Err(format!("Checkpoint has {} states, but {} were expected!", u64::from_le_bytes(len), state_count));
+        }
+        let dp = match width {
+            1 => DpCells::Narrow(Dp::new(read_atomics(&mut reader, state_count, AtomicU8::new)?, material, mode, scheme)),
+            2 => {
+                let bytes = read_atomics(&mut reader, 2 * state_count, |b| b)?;
+                DpCells::Wide(Dp::new(bytes.chunks(2).map(|b| AtomicU16::new(u16::from_le_bytes([b[0], b[1]]))).collect(), material, mode, scheme))
+            }
+            _ => return Err(format!("Checkpoint has {} bytes per state, only 1 and 2 are supported!", width)),
+        };
+        Ok(Checkpoint { material, mode, dp, layer, white_to_play })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::GenOptions;
+    use crate::tablebase::Tablebase;
+
+    #[test]
+    fn truncated_checkpoint_is_rejected() {
+        let material = Material::from_string("KRvK").unwrap();
+        let path = std::env::temp_dir().join(format!("3n2k_checkpoint_{}.ckp", std::process::id()));
+        let dp = Dp::new((0..Mode::Anywhere.state_count(material)).map(|i| AtomicU8::new(i as u8)).collect(), material, Mode::Anywhere, IndexScheme::Dense);
+        write_checkpoint(&path, material, Mode::Anywhere, &dp, 5, true);
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+        let result = Checkpoint::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.err().unwrap().contains("truncated"));
+    }
+
+    #[test]
+    fn resumed_generation_is_identical() {
+        let material = Material::from_string("KRvK").unwrap();
+        let path = std::env::temp_dir().join(format!("3n2k_resumed_{}.ckp", std::process::id()));
+        let complete = Tablebase::generate(2, material, Mode::Anywhere, GenOptions::default()).unwrap();
+        // stopping after a layer leaves the checkpoint a crash at that point would have left
+        Tablebase::generate(2, material, Mode::Anywhere, GenOptions { checkpoint: Some(&path), max_depth: Some(6), ..GenOptions::default() }).unwrap();
+        let checkpoint = Checkpoint::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.layer, 7);
+        let resumed = Tablebase::generate(3, material, Mode::Anywhere, GenOptions { resume: Some(checkpoint), ..GenOptions::default() }).unwrap();
+        assert_eq!(resumed.max_depth(), 0);
+        assert!(resumed.raw() == complete.raw());
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::Read;
+use std::io::Write;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use memmap::Mmap;
+use rayon::prelude::*;
+use indicatif::ProgressBar;
+
+// the slices have different sizes for both sides to move, so blocks can span two targets
+pub const BLOCK_SIZE: usize = 1 << 16;
+
+pub struct CompressedTable {
+    mmap: Mmap,
+    // offsets of the blocks in the mapping, the last one is the end of the file
+    offsets: Vec<usize>,
+    len: usize,
+    // BLOCK_SIZE, except for tablebases that are being migrated from an older index scheme
+    block_size: usize,
+}
+
+pub fn compress_blocks(dp: &[u8]) -> Vec<Vec<u8>> {
+    let bar = ProgressBar::new(((dp.len() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u64);
+    let blocks = dp.par_chunks(BLOCK_SIZE)
+        .map(|block| {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(block).unwrap();
+            bar.inc(1);
+            encoder.finish().unwrap()
+        })
+        .collect();
+    bar.finish();
+    blocks
+}
+
+// block count followed by the compressed length of each block and then the blocks themselves
+pub fn write_blocks<W: Write>(writer: &mut W, blocks: &[Vec<u8>]) -> std::io::Result<()> {
+    writer.write_all(&(blocks.len() as u64).to_le_bytes())?;
+    for block in blocks {
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+    }
+    for block in blocks {
+        writer.write_all(block)?;
+    }
+    Ok(())
+}
+
+impl CompressedTable {
+    pub fn open(mmap: Mmap, offset: usize, len: usize, block_size: usize) -> Result<Self, String> {
+        let block_count = (len + block_size - 1) / block_size;
+        let mut reader = &mmap[offset..];
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).map_err(|_| String::from("Compressed tablebase is truncated!"))?;
+        if u64::from_le_bytes(buf) != block_count as u64 {
+            return Err(format!("Compressed tablebase has {} blocks, but {} were expected!", u64::from_le_bytes(buf), block_count));
+        }
+        let mut offsets = Vec::with_capacity(block_count + 1);
+        let mut position = offset + 8 + 4 * block_count;
+        offsets.push(position);
+        for _ in 0..block_count {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf).map_err(|_| String::from("Compressed tablebase is truncated!"))?;
+            position += u32::from_le_bytes(buf) as usize;
+            offsets.push(position);
+        }
+        if position != mmap.len() {
+            return Err(String::from("Tablebase has the wrong size!"));
+        }
+        Ok(CompressedTable { mmap, offsets, len, block_size })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn block(&self, block: usize) -> Vec<u8> {
+        let size = if (block + 1) * self.block_size > self.len { self.len - block * self.block_size } else { self.block_size };
+        let mut result = vec![0u8; size];
+        DeflateDecoder::new(&self.mmap[self.offsets[block]..self.offsets[block + 1]]).read_exact(&mut result).unwrap();
+        result
+    }
+
+    pub fn get(&self, index: usize) -> u8 {
+        self.block(index / self.block_size)[index % self.block_size]
+    }
+
+    pub fn decompress(&self) -> Vec<u8> {
+        let bar = ProgressBar::new(self.block_count() as u64);
+        let mut dp = Vec::with_capacity(self.len);
+        for block in 0..self.block_count() {
+            dp.extend_from_slice(&self.block(block));
+            bar.inc(1);
+        }
+        bar.finish();
+        dp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use memmap::MmapOptions;
+
+    #[test]
+    fn compressed_round_trip() {
+        // runs like the draws of a real table, with a last block that isn't full
+        let len = 3 * BLOCK_SIZE + 1234;
+        let dp: Vec<u8> = (0..len).map(|i| if i % 7 < 4 { 255 } else { (i / 1000 % 200) as u8 }).collect();
+        let blocks = compress_blocks(&dp);
+        assert_eq!(blocks.len(), 4);
+        // the blocks follow the header in a file
+        let mut bytes = vec![1u8; 100];
+        write_blocks(&mut bytes, &blocks).unwrap();
+        let path = std::env::temp_dir().join(format!("3n2k_compressed_{}.tb", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let mmap = unsafe { MmapOptions::new().map(&File::open(&path).unwrap()) }.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let table = CompressedTable::open(mmap, 100, len, BLOCK_SIZE).unwrap();
+        assert_eq!(table.block_count(), 4);
+        for index in (0..len).step_by(997).chain(len - 10..len) {
+            assert_eq!(table.get(index), dp[index]);
+        }
+        assert!(table.decompress() == dp);
+    }
+}
@@ -1,8 +1,228 @@
+use crate::state::Position;
+use crate::target::{Target, parse_region, region_images};
+use crate::material::{Material, Piece, MAX_PIECES};
+
 pub type PackedState = u64;
 
+// The states with the same target and side to move form a slice, black to move comes first.
+// Every slice has a white king in the triangle and a black king that isn't next to it,
+// with white to move the knights also can't give check. See DenseState::encode.
+// These are the sizes for three knights, Material::slice_size has the ones of the other materials.
+pub const BLACK_SLICE_SIZE: usize = 21330480;
+pub const WHITE_SLICE_SIZE: usize = 16475054;
+// the slices of tablebases with target fields, other modes have a different number of targets
+pub const TARGET_COUNT: usize = 64;
+pub const SLICE_COUNT: usize = TARGET_COUNT * 2;
+
+// which checkmates count as a win for white, this decides the targets the index has slices for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // the black king has to be mated on the target field
+    Target,
+    // the black king can be mated on any square
+    Anywhere,
+    // the black king has to be mated on one of these squares
+    Region(u64),
+    // Like Target, but only some target fields have slices. Bit i is set if the target field
+    // with number i (see Position::to_u8_target) has one, this always includes its images.
+    Fields(u64),
+    // Like Target, but black helps white to mate on the target field instead of resisting.
+    // The values are the lengths of the shortest helpmates.
+    Helpmate,
+    // Like Target, but white has to stalemate the black king on the target field, mates are draws
+    Stalemate,
+}
+
+impl Mode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Mode::Target => 0,
+            Mode::Anywhere => 1,
+            Mode::Region(_) => 2,
+            Mode::Fields(_) => 3,
+            Mode::Helpmate => 4,
+            Mode::Stalemate => 5,
+        }
+    }
+    pub fn from_u8(i: u8, region: u64) -> Option<Self> {
+        match i {
+            0 => Some(Mode::Target),
+            1 => Some(Mode::Anywhere),
+            2 if region != 0 => Some(Mode::Region(region)),
+            3 if region != 0 => Some(Mode::Fields(region)),
+            4 => Some(Mode::Helpmate),
+            5 => Some(Mode::Stalemate),
+            _ => None,
+        }
+    }
+    pub fn from_string(s: &str) -> Result<Self, String> {
+        match s {
+            "target" => Ok(Mode::Target),
+            "anywhere" => Ok(Mode::Anywhere),
+            "helpmate" => Ok(Mode::Helpmate),
+            "stalemate" => Ok(Mode::Stalemate),
+            _ => Err(format!("{} is not a valid mode (has to be target, anywhere, helpmate or stalemate)!", s)),
+        }
+    }
+    pub fn from_region(s: &str) -> Result<Self, String> {
+        parse_region(s).map(Mode::Region)
+    }
+    // the given target fields and their images
+    pub fn from_fields(fields: &[Position]) -> Self {
+        let squares = fields.iter().flat_map(|field| region_images(1 << field.to_u8())).fold(0, |result, image| result | image);
+        Mode::Fields((0..TARGET_COUNT as u8).filter(|i| squares >> Position::from_u8_target(*i).to_u8() & 1 == 1).fold(0, |result, i| result | 1 << i))
+    }
+
+    // the squares of the region as they were given or the target fields with slices, 0 for the other modes
+    pub fn region(self) -> u64 {
+        match self {
+            Mode::Region(squares) | Mode::Fields(squares) => squares,
+            _ => 0,
+        }
+    }
+
+    // the target fields of the tablebase
+    pub fn has_fields(self) -> bool {
+        match self {
+            Mode::Target | Mode::Fields(_) | Mode::Helpmate | Mode::Stalemate => true,
+            _ => false,
+        }
+    }
+
+    // The mode of the tables after a capture. A table with only some target fields gets the ones with all of them,
+    // which serve every target field a partitioned tablebase may generate later.
+    pub fn capture_mode(self) -> Mode {
+        match self {
+            Mode::Fields(_) => Mode::Target,
+            _ => self,
+        }
+    }
+
+    // whether a table of this mode has the values of every target of the other one
+    pub fn covers(self, other: Mode) -> bool {
+        match (self, other) {
+            (Mode::Target, Mode::Fields(_)) => true,
+            (Mode::Fields(fields), Mode::Fields(other)) => other & !fields == 0,
+            _ => self == other,
+        }
+    }
+
+    pub fn is_helpmate(self) -> bool {
+        self == Mode::Helpmate
+    }
+
+    // the black king has to be stalemated instead of mated
+    pub fn is_stalemate(self) -> bool {
+        self == Mode::Stalemate
+    }
+
+    pub fn target_count(self) -> usize {
+        match self {
+            Mode::Target | Mode::Helpmate | Mode::Stalemate => TARGET_COUNT,
+            Mode::Anywhere => 1,
+            Mode::Region(squares) => region_images(squares).len(),
+            Mode::Fields(fields) => fields.count_ones() as usize,
+        }
+    }
+
+    // the target of the given slice
+    pub fn target(self, index: usize) -> Target {
+        match self {
+            Mode::Target | Mode::Helpmate | Mode::Stalemate => Target::Field(Position::from_u8_target(index as u8)),
+            Mode::Anywhere => Target::Anywhere,
+            Mode::Region(squares) => Target::Region(region_images(squares)[index]),
+            Mode::Fields(fields) => Target::Field(Position::from_u8_target((0..TARGET_COUNT as u8).filter(|i| fields >> i & 1 == 1).nth(index).unwrap())),
+        }
+    }
+
+    // the target of a position on the board, the target field is only needed with target fields
+    pub fn target_of(self, target_field: Option<Position>) -> Target {
+        match self {
+            Mode::Target | Mode::Fields(_) | Mode::Helpmate | Mode::Stalemate => Target::Field(target_field.unwrap()),
+            Mode::Anywhere => Target::Anywhere,
+            Mode::Region(squares) => Target::Region(squares),
+        }
+    }
+
+    // the slice of the target and the number of targets, like Target::slice but only counting the fields with slices
+    pub fn slice(self, target: Target) -> (u8, u8) {
+        match (self, target) {
+            (Mode::Fields(fields), Target::Field(field)) => ((fields & ((1 << field.to_u8_target()) - 1)).count_ones() as u8, fields.count_ones() as u8),
+            _ => target.slice(),
+        }
+    }
+
+    pub fn state_count(self, material: Material) -> usize {
+        self.target_count() * (material.slice_size(0) + material.slice_size(1))
+    }
+}
+
+impl Material {
+    // the states of one target with black (0) or white (1) to move
+    pub fn slice_size(self, white_to_move: usize) -> usize {
+        KING_OFFSETS[self.to_u8() as usize][white_to_move][10 * 64] as usize
+    }
+}
+
+// the slices of a tablebase with every target field
+pub fn slice_size(slice: usize, material: Material) -> usize {
+    material.slice_size(slice / TARGET_COUNT)
+}
+
+pub fn slice_offset(slice: usize, material: Material) -> usize {
+    let (black, white) = (material.slice_size(0), material.slice_size(1));
+    if slice < TARGET_COUNT { slice * black } else { TARGET_COUNT * black + (slice - TARGET_COUNT) * white }
+}
+
+pub fn slice_of(index: usize, material: Material) -> usize {
+    let (black, white) = (material.slice_size(0), material.slice_size(1));
+    if index < TARGET_COUNT * black { index / black } else { TARGET_COUNT + (index - TARGET_COUNT * black) / white }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexScheme {
+    // white king in the bottom left quadrant, only used by tablebases that still have to be migrated
+    Rotational,
+    // white king in the triangle below the a1-h8 diagonal, see SmallState::encode
+    Reflected,
+    // only legal positions, see DenseState::encode
+    Dense,
+    // the positions of Dense, but the black kings of a white king are interleaved, see INTERLEAVED
+    Interleaved,
+}
+
+impl IndexScheme {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            IndexScheme::Rotational => 0,
+            IndexScheme::Reflected => 1,
+            IndexScheme::Dense => 2,
+            IndexScheme::Interleaved => 3,
+        }
+    }
+    pub fn from_u8(i: u8) -> Option<Self> {
+        match i {
+            0 => Some(IndexScheme::Rotational),
+            1 => Some(IndexScheme::Reflected),
+            2 => Some(IndexScheme::Dense),
+            3 => Some(IndexScheme::Interleaved),
+            _ => None,
+        }
+    }
+    // the schemes a tablebase can be generated with
+    pub fn from_string(s: &str) -> Result<Self, String> {
+        match s {
+            "dense" => Ok(IndexScheme::Dense),
+            "interleaved" => Ok(IndexScheme::Interleaved),
+            _ => Err(format!("{} is not a valid index scheme (has to be dense or interleaved)!", s)),
+        }
+    }
+}
+
+// the digits of the reflected index scheme, which reserves an index for every placement of the pieces
 #[derive(Debug, Clone, Copy)]
 pub struct SmallState {
-    // < 16
+    // < 10
     pub white_king: u8,
     // sorted and < 60
     pub knights: [u8; 3],
@@ -36,35 +256,330 @@ lazy_static! {
         // println!("{}", table2.len()); -- 37820
         (table1, table2)
     };
+
+    // The squares that are left for the pieces for every white king in the triangle and black king square, with black and with white to move.
+    // With white to move the knights can't be on the squares where they give check, sliders use the squares for black to move.
+    static ref FREE_SQUARES: [Vec<u64>; 2] = {
+        let mut squares = [vec![0u64; 10 * 64], vec![0u64; 10 * 64]];
+        for white_king in 0..10 {
+            let white_king_pos = Position::from_u8_triangle(white_king);
+            for black_king in 0..64 {
+                let black_king_pos = Position::from_u8(black_king);
+                if white_king_pos == black_king_pos || white_king_pos.king_moves().contains(black_king_pos) {
+                    continue;
+                }
+                let free = !(1u64 << white_king_pos.to_u8()) & !(1u64 << black_king);
+                let checks = black_king_pos.knight_moves().iter().fold(0u64, |checks, pos| checks | 1 << pos.to_u8());
+                squares[0][white_king as usize * 64 + black_king as usize] = free;
+                squares[1][white_king as usize * 64 + black_king as usize] = free & !checks;
+            }
+        }
+        squares
+    };
+
+    // For every material, the index of the first state of every white king and black king square within a slice, followed by the slice size.
+    // The index is the material as a number, see Material::to_u8.
+    static ref KING_OFFSETS: Vec<[Vec<u32>; 2]> = {
+        let tables: Vec<[Vec<u32>; 2]> = (0..=255u8).map(|i| match Material::from_u8(i) {
+            Some(material) => {
+                let mut offsets = [vec![0u32], vec![0u32]];
+                for white_to_move in 0..2 {
+                    for pair in 0..10 * 64 {
+                        let count = if FREE_SQUARES[0][pair] == 0 { 0 } else { radices(material, white_to_move, pair).iter().product() };
+                        let last = *offsets[white_to_move].last().unwrap();
+                        offsets[white_to_move].push(last + count as u32);
+                    }
+                }
+                offsets
+            }
+            None => [vec![], vec![]],
+        }).collect();
+        let knights = &tables[Material::THREE_KNIGHTS.to_u8() as usize];
+        assert_eq!(knights[0][10 * 64] as usize, BLACK_SLICE_SIZE);
+        assert_eq!(knights[1][10 * 64] as usize, WHITE_SLICE_SIZE);
+        tables
+    };
+
+    // For every material and side to move, the slots of the pairs and the groups of every white king in the interleaved scheme.
+    // A white king keeps the states of Dense, but the black kings whose pairs have the same number of states form a group,
+    // which is ordered by the pieces first and by the black king second. So a move of the black king that doesn't pass
+    // any of the pieces stays within a few states, while Dense puts every black king after the other.
+    static ref INTERLEAVED: Vec<[(Vec<Slot>, Vec<Vec<Group>>); 2]> = (0..=255u8).map(|i| match Material::from_u8(i) {
+        Some(_) => {
+            let offsets = &KING_OFFSETS[i as usize];
+            let side = |white_to_move: usize| {
+                let offsets = &offsets[white_to_move];
+                let mut slots = vec![Slot { start: 0, kings: 0, position: 0 }; 10 * 64];
+                let mut groups = vec![];
+                for white_king in 0..10 {
+                    let mut kings: Vec<Group> = vec![];
+                    for black_king in 0..64 {
+                        let pair = white_king * 64 + black_king;
+                        let states = offsets[pair + 1] - offsets[pair];
+                        if states == 0 {
+                            continue;
+                        }
+                        match kings.iter_mut().find(|group| group.states == states) {
+                            Some(group) => group.kings.push(black_king as u8),
+                            None => kings.push(Group { start: 0, states, kings: vec![black_king as u8] }),
+                        }
+                    }
+                    let mut start = 0;
+                    for group in &mut kings {
+                        group.start = start;
+                        start += group.states * group.kings.len() as u32;
+                        for (position, black_king) in group.kings.iter().enumerate() {
+                            slots[white_king * 64 + *black_king as usize] = Slot { start: group.start, kings: group.kings.len() as u8, position: position as u8 };
+                        }
+                    }
+                    groups.push(kings);
+                }
+                (slots, groups)
+            };
+            [side(0), side(1)]
+        }
+        None => [(vec![], vec![]), (vec![], vec![])],
+    }).collect();
+}
+
+// where the states of a pair are in the interleaved scheme, relative to the first state of the white king
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    start: u32,
+    // the black kings of the group and the place of this one among them
+    kings: u8,
+    position: u8,
+}
+
+// black kings of a white king whose pairs have the same number of states
+struct Group {
+    start: u32,
+    states: u32,
+    kings: Vec<u8>,
+}
+
+fn binomial(n: u32, k: usize) -> usize {
+    let n = n as usize;
+    if n < k {
+        return 0;
+    }
+    match k {
+        0 => 1,
+        1 => n,
+        2 => n * (n - 1) / 2,
+        3 => n * (n - 1) * (n - 2) / 6,
+        _ => panic!("Only {} pieces of a kind are supported!", MAX_PIECES),
+    }
+}
+
+// the squares a kind of pieces can be on before the pieces of the following kinds are placed
+fn squares_of(piece: Piece, white_to_move: usize, pair: usize) -> u64 {
+    FREE_SQUARES[if piece == Piece::Knight { white_to_move } else { 0 }][pair]
+}
+
+// the number of combinations of every kind, each kind takes the squares that the previous ones left
+fn radices(material: Material, white_to_move: usize, pair: usize) -> Vec<usize> {
+    let mut placed = 0;
+    material.groups().iter().map(|(piece, pieces)| {
+        let free = squares_of(*piece, white_to_move, pair).count_ones() - placed;
+        placed += pieces.len() as u32;
+        binomial(free, pieces.len())
+    }).collect()
+}
+
+// the digits of the dense index scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DenseState {
+    // < 10
+    pub white_king: u8,
+    // < 64, not on or next to the white king
+    pub black_king: u8,
+    pub material: Material,
+    // The squares of the pieces, sorted within their kind, only the first material.count() are used.
+    // With white to move the knights don't attack the black king, the sliders may still do that,
+    // the indices of these illegal positions are never used.
+    pub pieces: [u8; MAX_PIECES],
+    // the slice of the target, see Target::slice
+    pub target: u8,
+    // < 256, the number of targets of the tablebase
+    pub targets: u8,
+    // < 2
+    pub white_to_move: u8,
+    // Dense or Interleaved, both have the same states
+    pub scheme: IndexScheme,
+}
+
+impl DenseState {
+    // The pieces of every kind are numbered among the squares that are left for them, their combination is ranked like in SmallState.
+    // The knights come first, so the squares that are left for the other kinds don't depend on where the knights are.
+    pub fn encode(&self) -> PackedState {
+        assert!(self.white_king < 10);
+        assert!(self.white_to_move < 2);
+        let white_to_move = self.white_to_move as usize;
+        let pair = self.white_king as usize * 64 + self.black_king as usize;
+        let mut taken = 0u64;
+        let mut combination = 0;
+        for (piece, pieces) in self.material.groups() {
+            let squares = squares_of(piece, white_to_move, pair) & !taken;
+            let mut rank = 0;
+            for (i, square) in self.pieces[pieces.clone()].iter().enumerate() {
+                assert!(squares >> square & 1 == 1);
+                rank += binomial((squares & ((1u64 << square) - 1)).count_ones(), i + 1);
+                taken |= 1 << square;
+            }
+            combination = combination * binomial(squares.count_ones(), pieces.len()) + rank;
+        }
+        assert!(self.target < self.targets);
+        let (black_size, white_size) = (self.material.slice_size(0), self.material.slice_size(1));
+        let slice_start = if self.white_to_move == 1 {
+            self.targets as usize * black_size + self.target as usize * white_size
+        }
+        else {
+            self.target as usize * black_size
+        };
+        let offsets = &KING_OFFSETS[self.material.to_u8() as usize][white_to_move];
+        let offset = match self.scheme {
+            IndexScheme::Interleaved => {
+                let slot = INTERLEAVED[self.material.to_u8() as usize][white_to_move].0[pair];
+                offsets[self.white_king as usize * 64] as usize + slot.start as usize + combination * slot.kings as usize + slot.position as usize
+            }
+            _ => offsets[pair] as usize + combination,
+        };
+        (slice_start + offset) as PackedState
+    }
+
+    pub fn decode(packed: PackedState, mode: Mode, material: Material, scheme: IndexScheme) -> Self {
+        let targets = mode.target_count();
+        let (black_size, white_size) = (material.slice_size(0), material.slice_size(1));
+        let (target, white_to_move) = if (packed as usize) < targets * black_size {
+            (packed as usize / black_size, 0)
+        }
+        else {
+            ((packed as usize - targets * black_size) / white_size, 1)
+        };
+        let slice_start = if white_to_move == 1 { targets * black_size + target * white_size } else { target * black_size };
+        let mut rest = packed as usize - slice_start;
+        // the last pair that starts at or before rest, pairs without states start at the same index as the next one
+        let offsets = &KING_OFFSETS[material.to_u8() as usize][white_to_move];
+        let (mut low, mut high) = (0, 10 * 64);
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if offsets[middle] as usize <= rest {
+                low = middle;
+            }
+            else {
+                high = middle;
+            }
+        }
+        if scheme == IndexScheme::Interleaved {
+            // the white king is the same in both schemes, the black king is the digit after the pieces in its group
+            let white_king = low / 64;
+            rest -= offsets[white_king * 64] as usize;
+            let group = INTERLEAVED[material.to_u8() as usize][white_to_move].1[white_king].iter()
+                .find(|group| rest < (group.start + group.states * group.kings.len() as u32) as usize)
+                .unwrap();
+            rest -= group.start as usize;
+            low = white_king * 64 + group.kings[rest % group.kings.len()] as usize;
+            rest /= group.kings.len();
+        }
+        else {
+            rest -= offsets[low] as usize;
+        }
+        // the rank of every kind, the first kind is the most significant digit
+        let radices = radices(material, white_to_move, low);
+        let mut ranks = vec![0; radices.len()];
+        for (rank, radix) in ranks.iter_mut().zip(&radices).rev() {
+            *rank = rest % radix;
+            rest /= radix;
+        }
+        let mut pieces = [0u8; MAX_PIECES];
+        let mut taken = 0u64;
+        for ((piece, range), mut rank) in material.groups().into_iter().zip(ranks) {
+            let squares = squares_of(piece, white_to_move, low) & !taken;
+            // the combinatorial number system, from the highest square down
+            for i in range.clone().rev() {
+                let k = i - range.start + 1;
+                let mut number = squares.count_ones();
+                while binomial(number, k) > rank {
+                    number -= 1;
+                }
+                rank -= binomial(number, k);
+                let mut remaining = squares;
+                for _ in 0..number {
+                    remaining &= remaining - 1;
+                }
+                pieces[i] = remaining.trailing_zeros() as u8;
+                taken |= 1 << pieces[i];
+            }
+        }
+        DenseState {
+            white_king: (low / 64) as u8,
+            black_king: (low % 64) as u8,
+            material,
+            pieces,
+            target: target as u8,
+            targets: targets as u8,
+            white_to_move: white_to_move as u8,
+            scheme,
+        }
+    }
 }
 
 impl SmallState {
     pub fn encode(&self) -> PackedState {
-        assert!(self.white_king < 16);
+        assert!(self.white_king < 10);
         assert!(self.black_king < 63);
         for knight in &self.knights {
             assert!(*knight < 60);
         }
         assert!(self.target_field < 28);
         assert!(self.white_to_move < 2);
-        self.white_king as u64 + 16 * (self.black_king as u64 + 63 * (KNIGHT_TABLES.0[to_knight_index(self.knights)] as u64 + KNIGHT_TABLES.1.len() as u64 * (self.target_field as u64 + 28 * self.white_to_move as u64)))
-    }
-    pub fn decode(mut packed: PackedState) -> Self {
-        let white_king = (packed % 16) as u8;
-        packed /= 16;
-        let black_king = (packed % 63) as u8;
-        packed /= 63;
-        let knights = KNIGHT_TABLES.1[packed as usize % KNIGHT_TABLES.1.len()];
-        packed /= KNIGHT_TABLES.1.len() as u64;
-        let target_field = (packed % 28) as u8;
-        packed /= 28;
-        let white_to_move = packed as u8;
-        SmallState {
-            white_king,
-            black_king,
-            target_field,
-            knights,
-            white_to_move,
+        self.white_king as u64 + 10 * (self.black_king as u64 + 63 * (KNIGHT_TABLES.0[to_knight_index(self.knights)] as u64 + KNIGHT_TABLES.1.len() as u64 * (self.target_field as u64 + 28 * self.white_to_move as u64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every step-th index decodes to digits that encode to it again
+    fn round_trip(material: Material, mode: Mode, scheme: IndexScheme, step: usize) {
+        for packed in (0..mode.state_count(material) as PackedState).step_by(step) {
+            let state = DenseState::decode(packed, mode, material, scheme);
+            assert_eq!(state.encode(), packed, "{:?}", state);
+            assert_eq!(DenseState::decode(state.encode(), mode, material, scheme), state);
+        }
+    }
+
+    #[test]
+    fn dense_round_trip() {
+        round_trip(Material::from_string("KRvK").unwrap(), Mode::Target, IndexScheme::Dense, 1);
+        round_trip(Material::from_string("KNNvK").unwrap(), Mode::Anywhere, IndexScheme::Dense, 1);
+        // the whole table has billions of states, a prime step still reaches every slice and pair
+        round_trip(Material::THREE_KNIGHTS, Mode::Target, IndexScheme::Dense, 9973);
+        round_trip(Material::THREE_KNIGHTS, Mode::Anywhere, IndexScheme::Dense, 9973);
+    }
+
+    #[test]
+    fn interleaved_round_trip() {
+        round_trip(Material::from_string("KRvK").unwrap(), Mode::Target, IndexScheme::Interleaved, 1);
+        round_trip(Material::THREE_KNIGHTS, Mode::Target, IndexScheme::Interleaved, 9973);
+    }
+
+    // the interleaved scheme numbers the same positions as the dense one, just in another order
+    fn same_positions(material: Material, mode: Mode) {
+        let count = mode.state_count(material);
+        let mut seen = vec![false; count];
+        for packed in 0..count as PackedState {
+            let dense = DenseState { scheme: IndexScheme::Dense, ..DenseState::decode(packed, mode, material, IndexScheme::Interleaved) }.encode() as usize;
+            assert!(!seen[dense], "{} is numbered twice", dense);
+            seen[dense] = true;
         }
     }
+
+    #[test]
+    fn interleaved_is_a_permutation_of_dense() {
+        same_positions(Material::from_string("KRvK").unwrap(), Mode::Target);
+        same_positions(Material::from_string("KNNvK").unwrap(), Mode::Anywhere);
+    }
 }
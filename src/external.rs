@@ -0,0 +1,467 @@
+use std::cmp::min;
+use std::fs::{File, OpenOptions, remove_file, rename};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, AtomicU16};
+use std::time::Instant;
+use rayon::{ThreadPool, scope};
+use crate::captures::Captures;
+use crate::encoding::{IndexScheme, Mode};
+use crate::header::Header;
+use crate::material::Material;
+use crate::search::{Dp, DtmCell, Frontier, GenOptions, NOT_CALCULATED, widen, is_counted, reach_black, waits_for_captures, defer_captures};
+use crate::state::{Position, State};
+use crate::tablebase::Tablebase;
+use crate::target::Target;
+
+// the indices are read from the work files in blocks of this many
+const BLOCK_LEN: usize = 1 << 16;
+// the indices of a block are split into tasks of this many for the thread pool
+const TASK_LEN: usize = 1 << 12;
+
+fn read_block<R: Read>(reader: &mut R, left: &mut u64, entry: usize, buffer: &mut Vec<u8>) -> bool {
+    let size = min(*left, (BLOCK_LEN * entry) as u64) as usize;
+    buffer.resize(size, 0);
+    reader.read_exact(buffer).unwrap();
+    *left -= size as u64;
+    size > 0
+}
+
+// The positions that are sent to the chunk their index is in, as offsets into the chunk.
+// They are appended to one file per chunk and applied when the chunk is in memory.
+struct Buckets {
+    paths: Vec<PathBuf>,
+    writers: Vec<Mutex<BufWriter<File>>>,
+    chunk_len: usize,
+}
+
+impl Buckets {
+    fn create(work: &Path, chunks: usize, chunk_len: usize) -> Self {
+        let paths: Vec<PathBuf> = (0..chunks).map(|chunk| work.with_extension(format!("bucket{}", chunk))).collect();
+        let writers = paths.iter().map(|path| Mutex::new(BufWriter::new(File::create(path).unwrap()))).collect();
+        Buckets { paths, writers, chunk_len }
+    }
+
+    fn push(&self, indices: &mut Vec<usize>) {
+        indices.sort_unstable();
+        let mut start = 0;
+        while start < indices.len() {
+            let chunk = indices[start] / self.chunk_len;
+            let end = start + indices[start..].iter().position(|index| index / self.chunk_len != chunk).unwrap_or(indices.len() - start);
+            let mut bytes = Vec::with_capacity(4 * (end - start));
+            for index in &indices[start..end] {
+                bytes.extend_from_slice(&((index - chunk * self.chunk_len) as u32).to_le_bytes());
+            }
+            self.writers[chunk].lock().unwrap().write_all(&bytes).unwrap();
+            start = end;
+        }
+        indices.clear();
+    }
+
+    // calls f with blocks of the indices sent to the chunk and empties its file, returns false if there were none
+    fn drain<F: FnMut(&[usize])>(&self, chunk: usize, mut f: F) -> bool {
+        let mut writer = self.writers[chunk].lock().unwrap();
+        writer.flush().unwrap();
+        let mut left = writer.get_ref().metadata().unwrap().len();
+        if left == 0 {
+            return false;
+        }
+        let mut reader = BufReader::new(File::open(&self.paths[chunk]).unwrap());
+        let mut buffer = vec![];
+        while read_block(&mut reader, &mut left, 4, &mut buffer) {
+            let indices: Vec<usize> = buffer.chunks(4).map(|bytes| chunk * self.chunk_len + u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize).collect();
+            f(&indices);
+        }
+        *writer = BufWriter::new(File::create(&self.paths[chunk]).unwrap());
+        true
+    }
+
+    fn remove(self) {
+        drop(self.writers);
+        for path in &self.paths {
+            remove_file(path).unwrap();
+        }
+    }
+}
+
+// The files of a generation with a memory limit. The dp array is on disk with the values and counters of Dp,
+// only one chunk of it is in memory at a time. The frontiers are files with the indices of their positions.
+struct Work {
+    material: Material,
+    mode: Mode,
+    scheme: IndexScheme,
+    len: usize,
+    chunk_len: usize,
+    dp: PathBuf,
+    frontier: PathBuf,
+    next: PathBuf,
+    buckets: Buckets,
+}
+
+impl Work {
+    fn create(work: &Path, material: Material, mode: Mode, scheme: IndexScheme, chunk_len: usize) -> Self {
+        let len = mode.state_count(material);
+        let chunks = (len + chunk_len - 1) / chunk_len;
+        let dp = work.with_extension("dp");
+        let mut writer = BufWriter::new(File::create(&dp).unwrap());
+        let buffer = vec![NOT_CALCULATED; BLOCK_LEN];
+        for start in (0..len).step_by(BLOCK_LEN) {
+            writer.write_all(&buffer[..min(BLOCK_LEN, len - start)]).unwrap();
+        }
+        writer.flush().unwrap();
+        Work {
+            material,
+            mode,
+            scheme,
+            len,
+            chunk_len,
+            dp,
+            frontier: work.with_extension("frontier"),
+            next: work.with_extension("next"),
+            buckets: Buckets::create(work, chunks, chunk_len),
+        }
+    }
+
+    fn chunks(&self) -> usize {
+        self.buckets.paths.len()
+    }
+
+    fn range(&self, chunk: usize) -> Range<usize> {
+        chunk * self.chunk_len..min((chunk + 1) * self.chunk_len, self.len)
+    }
+
+    fn load<C: DtmCell>(&self, chunk: usize) -> Dp<C> {
+        let range = self.range(chunk);
+        let mut file = File::open(&self.dp).unwrap();
+        file.seek(SeekFrom::Start((range.start * C::WIDTH) as u64)).unwrap();
+        let mut bytes = vec![0u8; range.len() * C::WIDTH];
+        file.read_exact(&mut bytes).unwrap();
+        let cells = bytes.chunks(C::WIDTH).map(|raw| C::from_raw(raw.iter().rev().fold(0, |value, byte| value << 8 | *byte as u16))).collect();
+        Dp::chunk(cells, range.start, self.material, self.mode, self.scheme)
+    }
+
+    // the values and counters, unlike Dp::to_bytes
+    fn store<C: DtmCell>(&self, chunk: usize, dp: &Dp<C>) {
+        let range = self.range(chunk);
+        let mut bytes = Vec::with_capacity(range.len() * C::WIDTH);
+        for index in range.clone() {
+            bytes.extend_from_slice(&dp.raw(index).to_le_bytes()[..C::WIDTH]);
+        }
+        let mut file = OpenOptions::new().write(true).open(&self.dp).unwrap();
+        file.seek(SeekFrom::Start((range.start * C::WIDTH) as u64)).unwrap();
+        file.write_all(&bytes).unwrap();
+    }
+
+    // continues the hash with the cells of every chunk, which is the same as Dp::hash of the whole array
+    fn hash<C: DtmCell>(&self, initial: u32) -> u32 {
+        (0..self.chunks()).fold(initial, |hash, chunk| self.load::<C>(chunk).hash(hash))
+    }
+
+    // switches the file to two bytes per state, like Dp::widen
+    fn widen(&self) {
+        let wide = self.dp.with_extension("wide");
+        {
+            let mut reader = BufReader::new(File::open(&self.dp).unwrap());
+            let mut writer = BufWriter::new(File::create(&wide).unwrap());
+            let mut left = self.len as u64;
+            let mut buffer = vec![];
+            while read_block(&mut reader, &mut left, 1, &mut buffer) {
+                let mut bytes = Vec::with_capacity(2 * buffer.len());
+                for raw in &buffer {
+                    bytes.extend_from_slice(&widen(*raw).to_le_bytes());
+                }
+                writer.write_all(&bytes).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        rename(&wide, &self.dp).unwrap();
+    }
+
+    // Sends the positions of the black kings and targets to the buckets, if the position matches the predicate.
+    // The positions are enumerated like in generate_checkmates, the predicate doesn't depend on the target.
+    fn send_states<F: Fn(&State) -> bool + Sync>(&self, kings: &[(Position, Vec<Target>)], predicate: F) {
+        let predicate = &predicate;
+        scope(|s| {
+            for white_king in 0..10 {
+                let white_king = Position::from_u8_triangle(white_king);
+                s.spawn(move |_| {
+                    let mut found = vec![];
+                    for (black_king, targets) in kings {
+                        let black_king = *black_king;
+                        if white_king.king_moves().contains(black_king) || white_king == black_king || targets.is_empty() {
+                            continue;
+                        }
+                        let free = !(1u64 << white_king.to_u8()) & !(1u64 << black_king.to_u8());
+                        for pieces in self.material.placements(free) {
+                            let state = State { white_king, material: self.material, pieces, black_king, target: targets[0], white_to_move: false };
+                            if predicate(&state) {
+                                found.extend(targets.iter().map(|target| State { target: *target, ..state }.pack_in(self.mode, self.scheme) as usize));
+                            }
+                        }
+                        self.buckets.push(&mut found);
+                    }
+                });
+            }
+        });
+    }
+
+    // sends the predecessors of the positions in the frontier, the black ones only for the moves they count
+    fn send_previous_states(&self, white_to_play: bool) {
+        let mut reader = BufReader::new(File::open(&self.frontier).unwrap());
+        let mut left = reader.get_ref().metadata().unwrap().len();
+        let mut buffer = vec![];
+        while read_block(&mut reader, &mut left, 8, &mut buffer) {
+            let block: Vec<usize> = buffer.chunks(8).map(|bytes| {
+                let mut index = [0u8; 8];
+                index.copy_from_slice(bytes);
+                u64::from_le_bytes(index) as usize
+            }).collect();
+            scope(|s| {
+                for task in block.chunks(TASK_LEN) {
+                    s.spawn(move |_| {
+                        let mut found = vec![];
+                        for index in task {
+                            let s = State::unpack(*index as u64, self.mode, self.material, self.scheme);
+                            for prev in s.previous_states() {
+                                if white_to_play && !is_counted(&s, &prev, self.mode) {
+                                    continue;
+                                }
+                                found.push(prev.pack_in(self.mode, self.scheme) as usize);
+                            }
+                        }
+                        self.buckets.push(&mut found);
+                    });
+                }
+            });
+        }
+    }
+
+    // Loads the chunks one after another and calls apply with the positions sent to them, then with the sorted due positions.
+    // The positions apply returns true for are written to the output file, returns how many there are.
+    fn apply<C, F, G>(&self, output: Option<&Path>, due: &[usize], apply: F, apply_due: G) -> usize where C: DtmCell, F: Fn(&Dp<C>, usize) -> bool + Sync, G: Fn(&Dp<C>, usize) -> bool {
+        let mut writer = output.map(|path| BufWriter::new(File::create(path).unwrap()));
+        let mut added = 0;
+        for chunk in 0..self.chunks() {
+            let range = self.range(chunk);
+            let start = range.start;
+            let due = &due[due.iter().position(|index| *index >= range.start).unwrap_or(due.len())..];
+            let due = &due[..due.iter().position(|index| *index >= range.end).unwrap_or(due.len())];
+            let mut dp = None;
+            let found = Frontier::new(range.len());
+            let sent = self.buckets.drain(chunk, |block| {
+                let dp = dp.get_or_insert_with(|| self.load::<C>(chunk));
+                let (dp, found, apply) = (&*dp, &found, &apply);
+                scope(|s| {
+                    for task in block.chunks(TASK_LEN) {
+                        s.spawn(move |_| {
+                            for index in task {
+                                if apply(dp, *index) {
+                                    found.insert(index - start);
+                                }
+                            }
+                        });
+                    }
+                });
+            });
+            if !sent && due.is_empty() {
+                continue;
+            }
+            let dp = dp.unwrap_or_else(|| self.load::<C>(chunk));
+            for index in due {
+                if apply_due(&dp, *index) {
+                    found.insert(index - start);
+                }
+            }
+            self.store(chunk, &dp);
+            added += found.count();
+            if let Some(writer) = &mut writer {
+                for index in found.indices() {
+                    writer.write_all(&((start + index) as u64).to_le_bytes()).unwrap();
+                }
+            }
+        }
+        if let Some(mut writer) = writer {
+            writer.flush().unwrap();
+        }
+        added
+    }
+
+    // writes the table with its header, the counters are not calculated like in Dp::to_bytes
+    fn write_table<C: DtmCell>(&self, path: &Path, max_depth: u16) {
+        let mut writer = BufWriter::new(File::create(path).unwrap());
+        let header = Header { max_depth, index_scheme: self.scheme, ..Header::new(self.material, self.mode, C::WIDTH as u8, 0) };
+        header.write(&mut writer).unwrap();
+        let mut hasher = crc32fast::Hasher::new();
+        for chunk in 0..self.chunks() {
+            let bytes = self.load::<C>(chunk).to_bytes();
+            hasher.update(&bytes);
+            writer.write_all(&bytes).unwrap();
+        }
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        Header { checksum: hasher.finalize(), ..header }.write(&mut writer).unwrap();
+        writer.flush().unwrap();
+    }
+
+    fn remove(self) {
+        remove_file(&self.dp).unwrap();
+        for path in &[&self.frontier, &self.next] {
+            if path.exists() {
+                remove_file(path).unwrap();
+            }
+        }
+        self.buckets.remove();
+    }
+}
+
+struct Progress {
+    layer: u16,
+    white_to_play: bool,
+    // positions in the frontier file
+    added: usize,
+    processed: usize,
+}
+
+// the layers of retrograde_search, with the work of a layer done in two passes over the chunks
+fn search_layers<C: DtmCell>(work: &Work, captures: &Captures, deferred: &Mutex<Vec<(u16, State)>>, progress: &mut Progress, options: &mut GenOptions) {
+    let mode = work.mode;
+    let max_depth = options.max_depth.unwrap_or(u16::MAX);
+    while (progress.added != 0 || !deferred.lock().unwrap().is_empty()) && progress.layer <= C::MAX_VALUE && progress.layer <= max_depth {
+        let start = Instant::now();
+        let white_to_play = progress.white_to_play;
+        let layer = progress.layer;
+
+        // positions deferred in this layer have a larger value, so the due ones can be taken first
+        let mut waiting = deferred.lock().unwrap();
+        let (due, later): (Vec<(u16, State)>, Vec<(u16, State)>) = waiting.drain(..).partition(|(value, _)| *value == layer);
+        *waiting = later;
+        drop(waiting);
+        let mut due: Vec<usize> = due.iter().map(|(_, s)| s.pack_in(mode, work.scheme) as usize).collect();
+        due.sort_unstable();
+
+        work.send_previous_states(white_to_play);
+        let added = work.apply::<C, _, _>(Some(&work.next), &due, |dp, packed| {
+            if white_to_play {
+                reach_black(dp, captures, mode, &dp.state_at(packed), packed, layer, deferred)
+            } else {
+                dp.set_if_not_calculated(packed, layer)
+            }
+        }, |dp, packed| dp.set_if_not_calculated(packed, layer));
+        rename(&work.next, &work.frontier).unwrap();
+
+        progress.processed += progress.added;
+        println!("{} positions found in layer {}. Processed {}/{} = {}% positions", added, layer, progress.processed, work.len, progress.processed as f32 * 100.0 / work.len as f32);
+        // the hash reads the whole dp file again, the time of the layer doesn't include that
+        let time = start.elapsed();
+        if let Some(report) = options.report.as_deref_mut() {
+            report.add_hashed_layer(|hash| work.hash::<C>(hash), layer, !white_to_play, added, time);
+        }
+        progress.added = added;
+        progress.white_to_play = !white_to_play;
+        progress.layer += 1;
+    }
+}
+
+// Generates the same table as retrograde_search, with about memory_limit bytes for the dp array and the buffers.
+// The work files are written next to the table, which is mapped from the path when it's done.
+// The tablebases of the materials after a capture are small enough to be generated in memory.
+// The report gets the same layers and hashes as the one of retrograde_search, checkpoints are not supported.
+pub fn external_search(pool: ThreadPool, material: Material, mode: Mode, memory_limit: usize, path: &Path, mut options: GenOptions) -> Result<Tablebase, String> {
+    let bound = options.max_depth.unwrap_or(u16::MAX);
+    let captures = Captures::generate(pool.current_num_threads(), material, mode)?;
+    println!("Generating {} tablebase with a memory limit of {} bytes...", material, memory_limit);
+    let tb = pool.install(|| {
+        // a chunk keeps its size when the table switches to two bytes per state
+        let chunk_len = (memory_limit / 4).max(1 << 16).min(u32::max_value() as usize);
+        let work = Work::create(path, material, mode, options.scheme, chunk_len);
+        println!("{} positions in {} chunks", work.len, work.chunks());
+        let deferred = Mutex::new(vec![]);
+
+        let start = Instant::now();
+        let targets: Vec<Target> = (0..mode.target_count()).map(|index| mode.target(index)).collect();
+        let mates: Vec<(Position, Vec<Target>)> = (0..64).map(Position::from_u8).map(|black_king| (black_king, targets.iter().cloned().filter(|target| target.contains(black_king)).collect())).collect();
+        work.send_states(&mates, |state| if mode.is_stalemate() { state.is_stalemate() } else { state.is_mate() });
+        let added = work.apply::<AtomicU8, _, _>(Some(&work.frontier), &[], |dp, packed| dp.set_if_not_calculated(packed, 0), |_, _| false);
+        println!("{} {} positions found", added, if mode.is_stalemate() { "stalemate" } else { "checkmate" });
+
+        let everywhere: Vec<(Position, Vec<Target>)> = (0..64).map(|black_king| (Position::from_u8(black_king), targets.clone())).collect();
+        work.send_states(&everywhere, |state| waits_for_captures(state, mode));
+        work.apply::<AtomicU8, _, _>(None, &[], |dp, packed| {
+            defer_captures(dp, &captures, mode, &dp.state_at(packed), packed, &deferred);
+            false
+        }, |_, _| false);
+        let time = start.elapsed();
+        if let Some(report) = options.report.as_deref_mut() {
+            report.add_hashed_layer(|hash| work.hash::<AtomicU8>(hash), 0, false, added, time);
+        }
+
+        let mut progress = Progress { layer: 1, white_to_play: false, added, processed: 0 };
+        search_layers::<AtomicU8>(&work, &captures, &deferred, &mut progress, &mut options);
+        let finished = progress.added == 0 && deferred.lock().unwrap().is_empty();
+        let width = if finished || progress.layer > bound {
+            1
+        }
+        else if !options.wide {
+            work.remove();
+            return Err(format!("Layer {} collides with the sentinel values of one byte tables, generate with --wide to store two bytes per state!", progress.layer));
+        }
+        else {
+            println!("Switching to two bytes per state for layer {}...", progress.layer);
+            work.widen();
+            search_layers::<AtomicU16>(&work, &captures, &deferred, &mut progress, &mut options);
+            let finished = progress.added == 0 && deferred.lock().unwrap().is_empty();
+            if !finished && progress.layer <= bound {
+                work.remove();
+                return Err(format!("Layer {} collides with the sentinel values of two byte tables!", progress.layer));
+            }
+            2
+        };
+
+        println!("Writing tablebase to disk...");
+        let finished = progress.added == 0 && deferred.lock().unwrap().is_empty();
+        let max_depth = if finished { 0 } else { bound };
+        if width == 1 {
+            work.write_table::<AtomicU8>(path, max_depth);
+        }
+        else {
+            work.write_table::<AtomicU16>(path, max_depth);
+        }
+        work.remove();
+        Tablebase::map_from_disk(File::open(path).unwrap())
+    })?;
+    Ok(tb.with_captures(captures))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::ThreadPoolBuilder;
+    use crate::report::Report;
+    use crate::search::retrograde_search;
+
+    // the layer, the new positions and the hash of every layer of a report
+    fn layers(report: &Report) -> Vec<(serde_json::Value, serde_json::Value, serde_json::Value)> {
+        serde_json::to_value(report).unwrap()["layers"].as_array().unwrap().iter()
+            .map(|layer| (layer["layer"].clone(), layer["new_positions"].clone(), layer["hash"].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn external_search_matches_retrograde_search() {
+        let material = Material::from_string("KRvK").unwrap();
+        // a few target fields, which are still spread over several chunks
+        let mode = Mode::from_fields(&[Position::from_u8(0), Position::from_u8(27)]);
+        let pool = || ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let mut report = Report::new(3, material, mode);
+        let tb = retrograde_search(pool(), material, mode, GenOptions { report: Some(&mut report), ..GenOptions::default() }).unwrap();
+        // the smallest chunks, so the positions are sent between lots of them
+        let path = std::env::temp_dir().join(format!("3n2k_external_{}.tb", std::process::id()));
+        let mut external_report = Report::new(3, material, mode);
+        let external = external_search(pool(), material, mode, 1, &path, GenOptions { report: Some(&mut external_report), ..GenOptions::default() }).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(external.len() > 4 << 16);
+        assert!(external.raw() == tb.raw());
+        assert_eq!(layers(&external_report), layers(&report));
+    }
+}
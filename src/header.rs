@@ -0,0 +1,254 @@
+use std::io::Read;
+use std::io::Write;
+use crate::search::{DRAW, NOT_CALCULATED, WIDE_DRAW, WIDE_NOT_CALCULATED};
+use crate::encoding::{IndexScheme, Mode};
+use crate::material::Material;
+
+pub const MAGIC: [u8; 8] = *b"3N2KTB\r\n";
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    // one byte per state
+    Raw,
+    // deflated blocks of BLOCK_SIZE states, see compression.rs
+    Compressed,
+    // one bit per state that is set if white can force mate, see wdl.rs
+    Bitmap,
+}
+
+impl Layout {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Layout::Raw => 0,
+            Layout::Compressed => 1,
+            Layout::Bitmap => 2,
+        }
+    }
+    pub fn from_u8(i: u8) -> Option<Self> {
+        match i {
+            0 => Some(Layout::Raw),
+            1 => Some(Layout::Compressed),
+            2 => Some(Layout::Bitmap),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub material: Material,
+    pub index_scheme: IndexScheme,
+    pub layout: Layout,
+    pub mode: Mode,
+    // the last layer of a table generated with --max-depth, 0 if the generation was completed
+    pub max_depth: u16,
+    // the materials after a capture, their tables are stored next to this one, see Captures::open
+    pub captures: Vec<Material>,
+    // bytes per state, 2 for tables with mates longer than 253 halfmoves
+    pub value_width: u8,
+    pub draw: u16,
+    pub not_calculated: u16,
+    // index of the first state in the file, non-zero for the slices of a partitioned tablebase
+    pub offset: u64,
+    pub entries: u64,
+    pub checksum: u32,
+}
+
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, String> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf).map_err(|e| format!("Tablebase header is truncated: {}", e))?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, String> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf).map_err(|e| format!("Tablebase header is truncated: {}", e))?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, String> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).map_err(|e| format!("Tablebase header is truncated: {}", e))?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, String> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).map_err(|e| format!("Tablebase header is truncated: {}", e))?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_material<R: Read>(reader: &mut R) -> Result<Material, String> {
+    let len = read_u8(reader)?;
+    let mut material = vec![0u8; len as usize];
+    reader.read_exact(&mut material).map_err(|e| format!("Tablebase header is truncated: {}", e))?;
+    let material = String::from_utf8(material).map_err(|_| String::from("Tablebase material is not valid UTF-8!"))?;
+    Material::from_string(&material)
+}
+
+// the length and the name of the material
+fn write_material<W: Write>(writer: &mut W, material: Material) -> std::io::Result<()> {
+    let material = material.to_string();
+    writer.write_all(&[material.len() as u8])?;
+    writer.write_all(material.as_bytes())
+}
+
+impl Header {
+    pub fn new(material: Material, mode: Mode, value_width: u8, checksum: u32) -> Self {
+        let (draw, not_calculated) = if value_width == 2 { (WIDE_DRAW, WIDE_NOT_CALCULATED) } else { (DRAW as u16, NOT_CALCULATED as u16) };
+        Header {
+            version: FORMAT_VERSION,
+            material,
+            index_scheme: IndexScheme::Dense,
+            layout: Layout::Raw,
+            mode,
+            max_depth: 0,
+            captures: material.capture_tables(),
+            value_width,
+            draw,
+            not_calculated,
+            offset: 0,
+            entries: mode.state_count(material) as u64,
+            checksum,
+        }
+    }
+
+    // magic, version, material length and string, index scheme, layout, mode, region, max depth,
+    // number of captures and their lengths and strings, value width, draw, not calculated, offset, entries, checksum
+    pub fn len(&self) -> usize {
+        let captures_len: usize = self.captures.iter().map(|captured| 1 + captured.to_string().len()).sum();
+        8 + 2 + 1 + self.material.to_string().len() + 1 + 1 + 1 + 8 + 2 + 1 + captures_len + 1 + 2 + 2 + 8 + 8 + 4
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        write_material(writer, self.material)?;
+        writer.write_all(&[self.index_scheme.to_u8(), self.layout.to_u8(), self.mode.to_u8()])?;
+        writer.write_all(&self.mode.region().to_le_bytes())?;
+        writer.write_all(&self.max_depth.to_le_bytes())?;
+        writer.write_all(&[self.captures.len() as u8])?;
+        for captured in &self.captures {
+            write_material(writer, *captured)?;
+        }
+        writer.write_all(&[self.value_width])?;
+        writer.write_all(&self.draw.to_le_bytes())?;
+        writer.write_all(&self.not_calculated.to_le_bytes())?;
+        writer.write_all(&self.offset.to_le_bytes())?;
+        writer.write_all(&self.entries.to_le_bytes())?;
+        writer.write_all(&self.checksum.to_le_bytes())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, String> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(|e| format!("Tablebase header is truncated: {}", e))?;
+        if magic != MAGIC {
+            return Err(String::from("Tablebase has no header (headerless files have to be converted with migrate --legacy)!"));
+        }
+        let version = read_u16(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(format!("Tablebase has format version {}, but only version {} is supported!", version, FORMAT_VERSION));
+        }
+        let material = read_material(reader)?;
+        let index_scheme = read_u8(reader)?;
+        let index_scheme = IndexScheme::from_u8(index_scheme).ok_or_else(|| format!("Tablebase uses the unknown index scheme {}!", index_scheme))?;
+        let layout = read_u8(reader)?;
+        let layout = Layout::from_u8(layout).ok_or_else(|| format!("Tablebase uses the unknown layout {}!", layout))?;
+        let mode = read_u8(reader)?;
+        let region = read_u64(reader)?;
+        let mode = Mode::from_u8(mode, region).ok_or_else(|| format!("Tablebase uses the unknown mode {}!", mode))?;
+        let max_depth = read_u16(reader)?;
+        let captures = (0..read_u8(reader)?).map(|_| read_material(reader)).collect::<Result<Vec<Material>, String>>()?;
+        let value_width = read_u8(reader)?;
+        let draw = read_u16(reader)?;
+        let not_calculated = read_u16(reader)?;
+        let offset = read_u64(reader)?;
+        let entries = read_u64(reader)?;
+        let checksum = read_u32(reader)?;
+        Ok(Header { version, material, index_scheme, layout, mode, max_depth, captures, value_width, draw, not_calculated, offset, entries, checksum })
+    }
+
+    // size of the data after the header, only known in advance for uncompressed layouts
+    pub fn data_len(&self) -> Option<u64> {
+        match self.layout {
+            Layout::Raw => Some(self.entries * self.value_width as u64),
+            Layout::Compressed => None,
+            Layout::Bitmap => Some((self.entries + 7) / 8),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset == 0 && self.entries == self.mode.state_count(self.material) as u64
+    }
+
+    pub fn verify_checksum(&self, data: &[u8]) -> Result<(), String> {
+        if checksum(data) != self.checksum {
+            Err(String::from("Tablebase checksum does not match, the file is corrupted!"))
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    pub fn check_compatible(&self) -> Result<(), String> {
+        if self.index_scheme != IndexScheme::Dense && self.index_scheme != IndexScheme::Interleaved {
+            Err(format!("Tablebase uses the index scheme {:?}, convert it with migrate first!", self.index_scheme))
+        }
+        else {
+            self.check_contents(self.mode.state_count(self.material))
+        }
+    }
+
+    // everything but the index scheme and the material, which determine the number of states
+    pub fn check_contents(&self, state_count: usize) -> Result<(), String> {
+        let expected = Header::new(self.material, self.mode, self.value_width, self.checksum);
+        if self.value_width != 1 && self.value_width != 2 {
+            Err(format!("Tablebase stores {} bytes per state, only 1 and 2 are supported!", self.value_width))
+        }
+        else if self.captures != expected.captures {
+            Err(format!("Tablebase lists the tables after a capture {:?}, but {} needs {:?}!", self.captures, self.material, expected.captures))
+        }
+        else if self.draw != expected.draw || self.not_calculated != expected.not_calculated {
+            Err(format!("Tablebase uses the sentinels {} (draw) and {} (not calculated), but {} and {} were expected!", self.draw, self.not_calculated, expected.draw, expected.not_calculated))
+        }
+        else if self.offset + self.entries > state_count as u64 {
+            Err(format!("Tablebase has the entries {} to {}, but only {} exist!", self.offset, self.offset + self.entries, state_count))
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header { max_depth: 40, index_scheme: IndexScheme::Interleaved, layout: Layout::Compressed, ..Header::new(Material::from_string("KBNvK").unwrap(), Mode::Region(0x81), 2, 0xdeadbeef) };
+        let mut bytes = vec![];
+        header.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), header.len());
+        assert_eq!(Header::read(&mut &bytes[..]).unwrap(), header);
+    }
+
+    #[test]
+    fn corrupted_checksum_is_rejected() {
+        let mut data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let header = Header::new(Material::THREE_KNIGHTS, Mode::Anywhere, 1, checksum(&data));
+        assert!(header.verify_checksum(&data).is_ok());
+        let corrupted = Header { checksum: header.checksum ^ 1, ..header.clone() };
+        assert!(corrupted.verify_checksum(&data).is_err());
+        data[500] ^= 1;
+        assert!(header.verify_checksum(&data).is_err());
+    }
+}
@@ -7,36 +7,187 @@
 #[macro_use] extern crate rocket_contrib;
 #[macro_use] extern crate serde_derive;
 
+mod captures;
+mod checkpoint;
+mod compression;
 mod encoding;
+mod external;
+mod header;
+mod material;
+mod migration;
 mod moves;
+mod partition;
+mod report;
 mod search;
 mod state;
 mod tablebase;
+mod target;
 mod verification;
+mod wdl;
 mod webserver;
 
 use crate::state::*;
 use std::fs::File;
 use std::time::Instant;
-use crate::tablebase::Tablebase;
-use clap::{Arg, App, SubCommand};
-use crate::tablebase::Value::MateIn;
+use crate::tablebase::{Tablebase, halfmove_clock};
+use clap::{Arg, App, SubCommand, ArgMatches};
+use crate::tablebase::Value::{MateIn, Win, Draw, Unknown};
 use std::path::Path;
 use crate::webserver::start_server;
 use chess::Board;
+use crate::compression::BLOCK_SIZE;
+use crate::encoding::{slice_of, slice_size, IndexScheme, Mode, TARGET_COUNT};
+use crate::checkpoint::Checkpoint;
+use crate::material::Material;
+use crate::migration::migrate;
+use crate::report::Report;
+use crate::search::GenOptions;
 
 
-fn gen(threads: usize, file: File) {
+// extend is the path of a bounded tablebase to continue, report the path the report is written to
+fn gen(threads: usize, output: &Path, partitioned: bool, material: Material, mode: Mode, memory_limit: Option<usize>, mut options: GenOptions) {
+    if partitioned && !mode.has_fields() {
+        exit_with_error(String::from("Only tablebases with target fields can be partitioned!"));
+    }
+    // the slices are always read as checkmates on the target field
+    if partitioned && mode.is_helpmate() {
+        exit_with_error(String::from("Helpmate tablebases can't be partitioned!"));
+    }
+    if partitioned && mode.is_stalemate() {
+        exit_with_error(String::from("Stalemate tablebases can't be partitioned!"));
+    }
+    // only a partitioned tablebase can have some of the target fields
+    let partitioned = match mode {
+        Mode::Fields(_) => true,
+        _ => partitioned,
+    };
+    // with a memory limit the table is generated in its file, a partitioned one is split up afterwards
+    let table = if partitioned { output.with_extension("table") } else { output.to_path_buf() };
+    let mut report = options.report.take();
+    let options = GenOptions { report: report.as_deref_mut(), ..options };
     let start = Instant::now();
-    let tb = Tablebase::generate(threads);
+    let tb = match memory_limit {
+        Some(memory_limit) => Tablebase::generate_external(threads, material, mode, memory_limit, &table, options),
+        None => Tablebase::generate(threads, material, mode, options),
+    }.unwrap_or_else(|e| exit_with_error(e));
+    if tb.max_depth() != 0 {
+        println!("Stopped after layer {}, continue with --extend", tb.max_depth());
+    }
     println!("Tablebase generated in {} seconds", start.elapsed().as_secs());
-    tb.write_to_disk(file);
+    if let Some(report) = report {
+        report.finish(&tb);
+    }
+    if partitioned {
+        tb.write_partitioned_to_disk(output);
+    }
+    else if memory_limit.is_none() {
+        tb.write_to_disk(File::create(output).unwrap());
+    }
+    // eval, validate and the server map them instead of generating them again
+    println!("Writing the tablebases after a capture to disk...");
+    tb.write_captures_to_disk(output).unwrap_or_else(|e| exit_with_error(e));
+    if partitioned && memory_limit.is_some() {
+        drop(tb);
+        std::fs::remove_file(table).unwrap();
+    }
+}
+
+// a number of bytes with an optional suffix, like 512M or 1G
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    match digits.parse::<usize>() {
+        Ok(size) if size > 0 => Ok(size * unit),
+        _ => Err(format!("{} is not a valid size (has to look like 512M or 1G)!", s)),
+    }
+}
+
+// Generates the table with every index scheme and probes the same positions with all of their moves in each of them.
+// The positions are spread over the whole table like the lookups of the search, so they hardly ever share a cache line.
+fn bench(threads: usize, material: Material, mode: Mode, probes: usize) {
+    let len = mode.state_count(material);
+    let states: Vec<State> = (0..probes as u64)
+        .map(|i| i.wrapping_mul(0x9E3779B97F4A7C15) % len as u64)
+        .map(|index| (index, State::unpack(index, mode, material, IndexScheme::Dense)))
+        .filter(|(index, state)| state.is_legal() && state.pack_in(mode, IndexScheme::Dense) == *index)
+        .map(|(_, state)| state)
+        .collect();
+    let mut results = vec![];
+    for scheme in &[IndexScheme::Dense, IndexScheme::Interleaved] {
+        println!("Benchmarking the {:?} index scheme...", scheme);
+        let start = Instant::now();
+        let tb = Tablebase::generate(threads, material, mode, GenOptions { scheme: *scheme, wide: true, ..GenOptions::default() }).unwrap_or_else(|e| exit_with_error(e));
+        let generation = start.elapsed();
+
+        let start = Instant::now();
+        let mut lookups = 0;
+        let mut sum = 0u64;
+        for state in &states {
+            sum += tb.get(tb.index_of(state)) as u64;
+            for next in state.next_states() {
+                sum += tb.get(tb.index_of(&next)) as u64;
+                lookups += 1;
+            }
+            lookups += 1;
+        }
+        let probing = start.elapsed();
+        results.push((*scheme, generation, lookups as f64 / probing.as_secs_f64(), sum));
+    }
+    println!("{} with {} threads, {} positions probed with their moves:", material, threads, states.len());
+    for (scheme, generation, throughput, _) in &results {
+        println!("{:?}: generated in {:.1} seconds, {:.0} lookups per second", scheme, generation.as_secs_f64(), throughput);
+    }
+    if results.iter().any(|(_, _, _, sum)| *sum != results[0].3) {
+        exit_with_error(String::from("The index schemes found different values for the same positions!"));
+    }
 }
 
-fn validate(threads: usize, file: File) {
-    let tb = Tablebase::read_from_disk(file).unwrap();
+fn exit_with_error(e: String) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1)
+}
+
+// a directory is read as a partitioned tablebase, restricted to the given targets if there are any
+fn read_dtm_tablebase(path: &Path, in_memory: bool) -> Tablebase {
+    let tb = read_tablebase(path, in_memory, None);
+    if !tb.has_distances() {
+        exit_with_error(format!("{} is a win/draw bitmap, but distances to mate are needed!", path.display()));
+    }
+    tb
+}
+
+fn read_tablebase(path: &Path, in_memory: bool, targets: Option<Vec<Position>>) -> Tablebase {
+    let tb = if path.is_dir() {
+        Tablebase::open_partitioned(path, in_memory, targets.as_ref().map(|targets| &targets[..]))
+    }
+    else {
+        let file = File::open(path).unwrap_or_else(|e| exit_with_error(format!("Could not open {}: {}", path.display(), e)));
+        if in_memory {
+            Tablebase::read_from_disk(file)
+        }
+        else {
+            Tablebase::map_from_disk(file)
+        }
+    };
+    tb.unwrap_or_else(|e| exit_with_error(e))
+}
+
+fn parse_targets(matches: &ArgMatches) -> Option<Vec<Position>> {
+    matches.values_of("targets").map(|targets| targets.map(|target| Position::from_string(&String::from(target)).unwrap_or_else(|e| exit_with_error(e))).collect())
+}
+
+fn validate(threads: usize, input: &Path, in_memory: bool) {
+    let mut tb = read_dtm_tablebase(input, in_memory);
+    if tb.max_depth() != 0 {
+        exit_with_error(format!("{} was only generated up to a depth of {}, extend it with gen --extend first!", input.display(), tb.max_depth()));
+    }
+    tb.load_captures(input).unwrap_or_else(|e| exit_with_error(e));
     let start = Instant::now();
-    if tb.verify(threads) {
+    if tb.verify(threads).unwrap_or_else(|e| exit_with_error(e)) {
         println!("The tablebase is consistent!");
     }
     else {
@@ -45,39 +196,177 @@ fn validate(threads: usize, file: File) {
     println!("Tablebase verified in {} seconds", start.elapsed().as_secs());
 }
 
-fn eval(file: File) {
-    let mut tb = Tablebase::read_from_disk(file).unwrap();
+// the most helpmate solutions eval prints
+const SOLUTION_LIMIT: usize = 20;
+
+fn eval(input: &Path, in_memory: bool, targets: Option<Vec<Position>>, rule50: bool) {
+    let mut tb = read_tablebase(input, in_memory, targets);
     tb.normalize();
+    tb.load_captures(input).unwrap_or_else(|e| exit_with_error(e));
     loop {
         let fen: String = read!("{}\n");
-        let target = read!("{}\n");
-        let os = Board::from_fen(fen);
-        let s = os.unwrap();
-        let target = Position::from_string(&target).unwrap();
-        let eval = tb.eval(s, target);
-        if let MateIn(n) = eval.value {
-            let mut first = true;
-            for m in &eval.best_moves {
-                if first {
-                    first = false
-                }
-                else {
-                    print!(", ")
+        // an empty line or the end of the input stops
+        if fen.trim().is_empty() {
+            break;
+        }
+        // tablebases without target fields don't ask for one, the line is read before any error skips the position
+        let target: Option<String> = if tb.mode().has_fields() { Some(read!("{}\n")) } else { None };
+        let clock = if rule50 {
+            match halfmove_clock(&fen) {
+                Ok(clock) => Some(clock),
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
                 }
-                print!("{}{}", m.get_source().to_string(), m.get_dest().to_string())
             }
-            println!("");
         }
         else {
-            println!("The position is an objective draw. Best moves:")
+            None
+        };
+        let s = match Board::from_fen(fen.clone()).filter(|board| board.is_sane()) {
+            Some(board) => board,
+            None => {
+                println!("{} is not a valid FEN!", fen);
+                continue;
+            }
+        };
+        let target = match target.map(|target| Position::from_string(&target)).transpose() {
+            Ok(target) => target,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        if let Some(target) = target.filter(|target| !tb.has_target(*target)) {
+            println!("The tablebase for target field {} is not loaded", target);
+            continue;
+        }
+        let eval = match tb.eval(s, target, clock) {
+            Ok(eval) => eval,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        match eval.in_time {
+            Some(true) => println!("The mate comes before the fifty-move rule draws the game"),
+            Some(false) => println!("The mate may come too late, the fifty-move rule draws the game if black defends well"),
+            None => {}
+        }
+        match eval.value {
+            MateIn(length) if tb.mode().is_helpmate() => {
+                let solutions = match tb.solutions(s, target, SOLUTION_LIMIT) {
+                    Ok(solutions) => solutions,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                };
+                if solutions.len() < SOLUTION_LIMIT {
+                    println!("Helpmate in {} halfmoves with {} solutions:", length, solutions.len());
+                }
+                else {
+                    println!("Helpmate in {} halfmoves, the first {} solutions:", length, SOLUTION_LIMIT);
+                }
+                for solution in solutions {
+                    let moves: Vec<String> = solution.iter().map(|m| format!("{}{}", m.get_source().to_string(), m.get_dest().to_string())).collect();
+                    println!("{}", moves.join(" "));
+                }
+            }
+            MateIn(_) | Win => {
+                if let Win = eval.value {
+                    print!("White can force mate (the distance is unknown). Best moves: ")
+                }
+                let mut first = true;
+                for m in &eval.best_moves {
+                    if first {
+                        first = false
+                    }
+                    else {
+                        print!(", ")
+                    }
+                    print!("{}{}", m.get_source().to_string(), m.get_dest().to_string())
+                }
+                println!("");
+            }
+            Draw if tb.mode().is_helpmate() => println!("Black can't be mated on the target field, not even with its help"),
+            Draw => println!("The position is an objective draw. Best moves:"),
+            Unknown => println!("The position is not decided within the first {} halfmoves the tablebase was generated for", tb.max_depth()),
         }
     }
 }
 
-fn server(file: File) {
-    let mut tb = Tablebase::read_from_disk(file).unwrap();
+// the tables after a capture are written next to every tablebase, opening it only maps them, see Captures::open
+fn copy_captures(tb: &mut Tablebase, input: &Path, output: &Path) {
+    tb.load_captures(input).unwrap_or_else(|e| exit_with_error(e));
+    println!("Writing the tablebases after a capture to disk...");
+    tb.write_captures_to_disk(output).unwrap_or_else(|e| exit_with_error(e));
+}
+
+fn create(path: &Path) -> File {
+    File::create(path).unwrap_or_else(|e| exit_with_error(format!("Could not create {}: {}", path.display(), e)))
+}
+
+fn compress(input: &Path, output: &Path) {
+    let mut tb = read_dtm_tablebase(input, false);
+    let start = Instant::now();
+    let blocks = tb.write_compressed_to_disk(create(output));
+    println!("Tablebase compressed in {} seconds", start.elapsed().as_secs());
+    copy_captures(&mut tb, input, output);
+    let total: usize = blocks.iter().sum();
+    let len = tb.len() * tb.width();
+    if tb.mode() != Mode::Target {
+        println!("Total: {} -> {} bytes (ratio {:.2})", len, total, len as f64 / total as f64);
+        return;
+    }
+    // blocks that span two targets are counted for the first one
+    let mut compressed = [0usize; TARGET_COUNT];
+    for (i, size) in blocks.iter().enumerate() {
+        compressed[slice_of(i * BLOCK_SIZE / tb.width(), tb.material()) % TARGET_COUNT] += size;
+    }
+    for target in 0..TARGET_COUNT {
+        let target_bytes = (slice_size(target, tb.material()) + slice_size(target + TARGET_COUNT, tb.material())) * tb.width();
+        println!("{}: {} -> {} bytes (ratio {:.2})", Position::from_u8_target(target as u8), target_bytes, compressed[target], target_bytes as f64 / compressed[target] as f64);
+    }
+    println!("Total: {} -> {} bytes (ratio {:.2})", len, total, len as f64 / total as f64);
+}
+
+fn decompress(input: &Path, output: &Path) {
+    let mut tb = read_dtm_tablebase(input, false);
+    tb.write_to_disk(create(output));
+    copy_captures(&mut tb, input, output);
+}
+
+fn split(input: &Path, output: &Path) {
+    let mut tb = read_dtm_tablebase(input, false);
+    if tb.mode() != Mode::Target {
+        exit_with_error(String::from("Only tablebases with target fields can be split!"));
+    }
+    tb.write_partitioned_to_disk(output);
+    copy_captures(&mut tb, input, output);
+}
+
+// the bitmaps of the tables after a capture are written next to the output, eval of the bitmap only needs those
+fn wdl(input: &Path, output: &Path) {
+    let mut tb = read_dtm_tablebase(input, false);
+    tb.load_captures(input).unwrap_or_else(|e| exit_with_error(e));
+    tb.write_wdl_to_disk(create(output));
+    tb.write_wdl_captures_to_disk(output).unwrap_or_else(|e| exit_with_error(e));
+}
+
+fn migrate_tablebase(input: &Path, output: &Path, legacy: bool) {
+    let file = File::open(input).unwrap_or_else(|e| exit_with_error(format!("Could not open {}: {}", input.display(), e)));
+    let start = Instant::now();
+    migrate(&file, legacy, output).unwrap_or_else(|e| exit_with_error(e));
+    println!("Tablebase migrated in {} seconds", start.elapsed().as_secs());
+}
+
+fn server(threads: usize, input: &Path, in_memory: bool, targets: Option<Vec<Position>>, rule50: bool) {
+    let mut tb = read_tablebase(input, in_memory, targets);
     tb.normalize();
-    start_server(tb);
+    // the captures are mapped before the server starts, a missing table stops it right away
+    tb.load_captures(input).unwrap_or_else(|e| exit_with_error(e));
+    start_server(tb, threads, rule50);
 }
 
 
@@ -91,6 +380,74 @@ fn main() {
                 .help("The output file")
                 .required(true)
                 .index(1))
+            .arg(Arg::with_name("partitioned")
+                .long("partitioned")
+                .help("Writes one file per target field and side to move into the output directory"))
+            .arg(Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
+                .value_name("file")
+                .help("Writes a checkpoint to this file after every layer"))
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .takes_value(true)
+                .value_name("checkpoint")
+                .help("Continues the generation from a checkpoint, new checkpoints are written to the same file unless --checkpoint is given"))
+            .arg(Arg::with_name("material")
+                .long("material")
+                .takes_value(true)
+                .value_name("material")
+                .default_value("KNNNvK")
+                .help("The white king with up to three knights, bishops, rooks and queens against the king, like KNNNvK or \"KBN v K\". Ignored with --resume"))
+            .arg(Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .value_name("mode")
+                .default_value("target")
+                .help("target (mate on the target field), anywhere (mate on any square), helpmate (black helps to mate on the target field) or stalemate (stalemate on the target field), ignored with --resume"))
+            .arg(Arg::with_name("region")
+                .long("region")
+                .takes_value(true)
+                .value_name("squares")
+                .help("Only mates on these squares count: corners, rim, a-file, h-file, 1st-rank, 8th-rank or a list like a1,a8,h8. Ignored with --resume"))
+            .arg(Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .value_name("square")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only generates this target field and its images, can be given more than once. The output is written as a partitioned tablebase. Ignored with --resume"))
+            .arg(Arg::with_name("max-depth")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("halfmoves")
+                .help("Stops after this many layers, positions that aren't decided by then are unknown instead of drawn"))
+            .arg(Arg::with_name("extend")
+                .long("extend")
+                .takes_value(true)
+                .value_name("tablebase")
+                .conflicts_with("resume")
+                .help("Continues a tablebase generated with --max-depth, up to the new --max-depth or until it is complete"))
+            .arg(Arg::with_name("wide")
+                .long("wide")
+                .help("Stores two bytes per state once the mates get longer than 253 halfmoves, instead of stopping"))
+            .arg(Arg::with_name("index-scheme")
+                .long("index-scheme")
+                .takes_value(true)
+                .value_name("scheme")
+                .default_value("dense")
+                .help("dense (the pieces vary faster than the kings) or interleaved (the black king varies fastest, so most of its moves stay within a cache line). Ignored with --resume and --extend"))
+            .arg(Arg::with_name("memory-limit")
+                .long("memory-limit")
+                .takes_value(true)
+                .value_name("size")
+                .conflicts_with_all(&["resume", "extend", "checkpoint"])
+                .help("Keeps the table on disk and only this much of it in memory, like 512M or 1G. The work files are written next to the output"))
+            .arg(Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .value_name("file")
+                .help("Writes a JSON report with the positions, time and a hash of the table for every layer, and the longest mate of every target. The hashes are the same with any number of threads and with --memory-limit, which reads the whole table from disk for them"))
             .arg(Arg::with_name("threads")
                 .short("t")
                 .long("threads")
@@ -99,10 +456,38 @@ fn main() {
                 .required(false)
                 .default_value("7")
                 .help("The amount of threads to use")))
+        .subcommand(SubCommand::with_name("bench")
+            .about("compares the generation time and the probing throughput of the index schemes")
+            .arg(Arg::with_name("material")
+                .long("material")
+                .takes_value(true)
+                .value_name("material")
+                .default_value("KNNvK")
+                .help("The material to generate, like KNNvK"))
+            .arg(Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .value_name("mode")
+                .default_value("target")
+                .help("target, anywhere, helpmate or stalemate"))
+            .arg(Arg::with_name("probes")
+                .long("probes")
+                .takes_value(true)
+                .value_name("n")
+                .default_value("1000000")
+                .help("How many positions are probed with all of their moves"))
+            .arg(Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .value_name("n")
+                .required(false)
+                .default_value("7")
+                .help("The amount of threads to use for the generation")))
         .subcommand(SubCommand::with_name("validate")
             .about("validates the tablebase (this takes a long time)")
             .arg(Arg::with_name("input")
-                .help("The tablebase file")
+                .help("The tablebase file or directory")
                 .required(true)
                 .index(1))
             .arg(Arg::with_name("threads")
@@ -112,38 +497,193 @@ fn main() {
                 .value_name("n")
                 .required(false)
                 .default_value("7")
-                .help("The amount of threads to use")))
+                .help("The amount of threads to use"))
+            .arg(Arg::with_name("in-memory")
+                .long("in-memory")
+                .help("Reads the whole tablebase into memory and checks its checksum instead of mapping the file")))
         .subcommand(SubCommand::with_name("eval")
             .about("reads FENs and target squares (each on their own line) from stdin and evaluates the positions")
+            .arg(Arg::with_name("input")
+                .help("The tablebase file or directory")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("in-memory")
+                .long("in-memory")
+                .help("Reads the whole tablebase into memory and checks its checksum instead of mapping the file"))
+            .arg(Arg::with_name("rule50")
+                .long("rule50")
+                .help("Reads the halfmove clock from the FEN and checks whether the mate comes before the fifty-move rule draws the game"))
+            .arg(Arg::with_name("targets")
+                .long("targets")
+                .takes_value(true)
+                .value_name("squares")
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Only loads these target fields of a partitioned tablebase, instead of loading every target on first use")))
+        .subcommand(SubCommand::with_name("compress")
+            .about("converts a tablebase into the block compressed format")
+            .arg(Arg::with_name("input")
+                .help("The tablebase file or directory")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("output")
+                .help("The compressed output file")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("decompress")
+            .about("converts a block compressed tablebase back into the raw format")
+            .arg(Arg::with_name("input")
+                .help("The compressed tablebase file")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("output")
+                .help("The output file")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("split")
+            .about("splits a tablebase into one file per target field and side to move")
+            .arg(Arg::with_name("input")
+                .help("The tablebase file")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("output")
+                .help("The output directory")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("wdl")
+            .about("builds a win/draw bitmap from a tablebase and the ones of the tables after a capture, they only need an eighth of the memory")
+            .arg(Arg::with_name("input")
+                .help("The tablebase file or directory")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("output")
+                .help("The output file")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("converts a tablebase written by older versions into a partitioned tablebase with the target fields on the rim")
             .arg(Arg::with_name("input")
                 .help("The tablebase file")
                 .required(true)
-                .index(1)))
+                .index(1))
+            .arg(Arg::with_name("output")
+                .help("The output directory")
+                .required(true)
+                .index(2))
+            .arg(Arg::with_name("legacy")
+                .long("legacy")
+                .help("Reads a headerless tablebase file written by older versions")))
         .subcommand(SubCommand::with_name("server")
             .about("Launches a webserver with a simple ui")
             .arg(Arg::with_name("input")
-                .help("The tablebase file")
+                .help("The tablebase file or directory")
                 .required(true)
-                .index(1)))
+                .index(1))
+            .arg(Arg::with_name("in-memory")
+                .long("in-memory")
+                .help("Reads the whole tablebase into memory and checks its checksum instead of mapping the file"))
+            .arg(Arg::with_name("rule50")
+                .long("rule50")
+                .help("Reads the halfmove clock from the FEN and checks whether the mate comes before the fifty-move rule draws the game"))
+            .arg(Arg::with_name("targets")
+                .long("targets")
+                .takes_value(true)
+                .value_name("squares")
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Only loads these target fields of a partitioned tablebase, instead of loading every target on first use"))
+            .arg(Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .value_name("n")
+                .required(false)
+                .default_value("7")
+                .help("The amount of threads to use for generating target fields that are missing from a partitioned tablebase")))
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("gen") {
-        let file = File::create(Path::new(matches.value_of("output").unwrap())).unwrap();
+        let output = Path::new(matches.value_of("output").unwrap());
+        let threads = matches.value_of("threads").unwrap().parse().unwrap();
+        let resume = matches.value_of("resume").map(Path::new);
+        let checkpoint = matches.value_of("checkpoint").map(Path::new).or(resume);
+        let modes = (matches.occurrences_of("mode") > 0) as usize + matches.is_present("region") as usize + matches.is_present("target") as usize;
+        if modes > 1 {
+            exit_with_error(String::from("Only one of --mode, --region and --target can be used!"));
+        }
+        let mode = match (matches.value_of("region"), matches.values_of("target")) {
+            (Some(region), _) => Mode::from_region(region),
+            (None, Some(targets)) => targets.map(|target| Position::from_string(&String::from(target))).collect::<Result<Vec<Position>, String>>().map(|targets| Mode::from_fields(&targets)),
+            (None, None) => Mode::from_string(matches.value_of("mode").unwrap()),
+        }.unwrap_or_else(|e| exit_with_error(e));
+        let material = Material::from_string(matches.value_of("material").unwrap()).unwrap_or_else(|e| exit_with_error(e));
+        let max_depth = matches.value_of("max-depth").map(|depth| match depth.parse() {
+            Ok(depth) if depth > 0 => depth,
+            _ => exit_with_error(format!("{} is not a valid depth!", depth)),
+        });
+        // a bounded tablebase is extended by resuming from its checkpoint
+        let resume = match (resume, matches.value_of("extend")) {
+            (Some(path), _) => Some(Checkpoint::read(path)),
+            (None, Some(path)) => Some(read_dtm_tablebase(Path::new(path), false).checkpoint(threads, max_depth)),
+            (None, None) => None,
+        }.map(|checkpoint| checkpoint.unwrap_or_else(|e| exit_with_error(e)));
+        // a resumed or extended generation keeps the material and mode it was started with
+        let (material, mode) = resume.as_ref().map_or((material, mode), |checkpoint| (checkpoint.material, checkpoint.mode));
+        let memory_limit = matches.value_of("memory-limit").map(|size| parse_size(size).unwrap_or_else(|e| exit_with_error(e)));
+        let scheme = IndexScheme::from_string(matches.value_of("index-scheme").unwrap()).unwrap_or_else(|e| exit_with_error(e));
+        let report = matches.value_of("report").map(Path::new);
+        let mut layers = report.map(|_| Report::new(threads, material, mode));
+        let options = GenOptions { scheme, checkpoint, resume, wide: matches.is_present("wide"), max_depth, report: layers.as_mut() };
+        gen(threads, output, matches.is_present("partitioned"), material, mode, memory_limit, options);
+        if let (Some(path), Some(layers)) = (report, layers) {
+            layers.write(path).unwrap_or_else(|e| exit_with_error(e));
+        }
+    }
+    else if let Some(matches) = matches.subcommand_matches("bench") {
         let threads = matches.value_of("threads").unwrap().parse().unwrap();
-        gen(threads, file);
+        let material = Material::from_string(matches.value_of("material").unwrap()).unwrap_or_else(|e| exit_with_error(e));
+        let mode = Mode::from_string(matches.value_of("mode").unwrap()).unwrap_or_else(|e| exit_with_error(e));
+        let probes = matches.value_of("probes").unwrap().parse().unwrap_or_else(|_| exit_with_error(String::from("The number of probes has to be a number!")));
+        bench(threads, material, mode, probes);
     }
     else if let Some(matches) = matches.subcommand_matches("validate") {
-        let file = File::open(Path::new(matches.value_of("input").unwrap())).unwrap();
+        let input = Path::new(matches.value_of("input").unwrap());
         let threads = matches.value_of("threads").unwrap().parse().unwrap();
-        validate(threads, file);
+        validate(threads, input, matches.is_present("in-memory"));
     }
     else if let Some(matches) = matches.subcommand_matches("eval") {
-        let file = File::open(Path::new(matches.value_of("input").unwrap())).unwrap();
-        eval(file);
+        let input = Path::new(matches.value_of("input").unwrap());
+        eval(input, matches.is_present("in-memory"), parse_targets(matches), matches.is_present("rule50"));
+    }
+    else if let Some(matches) = matches.subcommand_matches("compress") {
+        let input = Path::new(matches.value_of("input").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+        compress(input, output);
+    }
+    else if let Some(matches) = matches.subcommand_matches("decompress") {
+        let input = Path::new(matches.value_of("input").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+        decompress(input, output);
+    }
+    else if let Some(matches) = matches.subcommand_matches("split") {
+        let input = Path::new(matches.value_of("input").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+        split(input, output);
+    }
+    else if let Some(matches) = matches.subcommand_matches("wdl") {
+        let input = Path::new(matches.value_of("input").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+        wdl(input, output);
+    }
+    else if let Some(matches) = matches.subcommand_matches("migrate") {
+        let input = Path::new(matches.value_of("input").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+        migrate_tablebase(input, output, matches.is_present("legacy"));
     }
     else if let Some(matches) = matches.subcommand_matches("server") {
-        let file = File::open(Path::new(matches.value_of("input").unwrap())).unwrap();
-        server(file);
+        let input = Path::new(matches.value_of("input").unwrap());
+        let threads = matches.value_of("threads").unwrap().parse().unwrap();
+        server(threads, input, matches.is_present("in-memory"), parse_targets(matches), matches.is_present("rule50"));
     }
 
 
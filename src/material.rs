@@ -0,0 +1,168 @@
+use std::fmt;
+use std::ops::Range;
+use crate::state::Position;
+
+// the white pieces besides the king
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Piece {
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+}
+
+// the order of the kinds in a state
+pub const PIECES: [Piece; 4] = [Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen];
+// the most pieces a tablebase can have besides the kings
+pub const MAX_PIECES: usize = 3;
+
+impl Piece {
+    pub fn to_char(self) -> char {
+        match self {
+            Piece::Knight => 'N',
+            Piece::Bishop => 'B',
+            Piece::Rook => 'R',
+            Piece::Queen => 'Q',
+        }
+    }
+    pub fn from_char(c: char) -> Option<Self> {
+        PIECES.iter().cloned().find(|piece| piece.to_char() == c)
+    }
+}
+
+// The white king and up to three pieces against the lone black king, like KNNNvK or KBNvK.
+// The pieces of a state are sorted by their kind in the order of PIECES, see State::pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Material {
+    // the number of pieces of every kind
+    counts: [u8; 4],
+}
+
+impl Material {
+    pub const THREE_KNIGHTS: Material = Material { counts: [3, 0, 0, 0] };
+
+    pub fn from_pieces(pieces: &[Piece]) -> Self {
+        assert!(pieces.len() <= MAX_PIECES, "Only {} pieces besides the kings are supported!", MAX_PIECES);
+        let mut counts = [0u8; 4];
+        for piece in pieces {
+            counts[*piece as usize] += 1;
+        }
+        Material { counts }
+    }
+
+    // like KNNNvK, the spaces of KNNN v K are ignored
+    pub fn from_string(s: &str) -> Result<Self, String> {
+        let name: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let sides: Vec<&str> = name.split('v').collect();
+        if sides.len() != 2 || !sides[0].starts_with('K') {
+            return Err(format!("{} is not a valid material (has to look like KNNNvK)!", s));
+        }
+        if sides[1] != "K" {
+            return Err(format!("{} is not a valid material (black can only have the king)!", s));
+        }
+        let pieces = sides[0][1..].chars().map(|c| Piece::from_char(c).ok_or_else(|| format!("{} is not a valid material ({} is not a knight, bishop, rook or queen)!", s, c))).collect::<Result<Vec<Piece>, String>>()?;
+        if pieces.is_empty() || pieces.len() > MAX_PIECES {
+            return Err(format!("{} is not a valid material (white needs one to {} pieces besides the king)!", s, MAX_PIECES));
+        }
+        Ok(Material::from_pieces(&pieces))
+    }
+
+    // the counts as digits in base 4
+    pub fn to_u8(self) -> u8 {
+        self.counts.iter().rev().fold(0, |result, count| result * 4 + count)
+    }
+    pub fn from_u8(i: u8) -> Option<Self> {
+        let counts = [i & 3, i >> 2 & 3, i >> 4 & 3, i >> 6 & 3];
+        if counts.iter().sum::<u8>() as usize > MAX_PIECES {
+            None
+        }
+        else {
+            Some(Material { counts })
+        }
+    }
+
+    pub fn count(self) -> usize {
+        self.counts.iter().sum::<u8>() as usize
+    }
+
+    // the kind of the i-th piece of a state
+    pub fn kind(self, i: usize) -> Piece {
+        let mut end = 0;
+        for piece in &PIECES {
+            end += self.counts[*piece as usize] as usize;
+            if i < end {
+                return *piece;
+            }
+        }
+        panic!("{} only has {} pieces besides the kings!", self, self.count());
+    }
+
+    // the kinds with the pieces of the state that have them
+    pub fn groups(self) -> Vec<(Piece, Range<usize>)> {
+        let mut groups = vec![];
+        let mut start = 0;
+        for piece in &PIECES {
+            let count = self.counts[*piece as usize] as usize;
+            if count > 0 {
+                groups.push((*piece, start..start + count));
+                start += count;
+            }
+        }
+        groups
+    }
+
+    // every way to put the pieces on the free squares, the pieces of a kind are sorted
+    pub fn placements(self, free: u64) -> Vec<[Position; MAX_PIECES]> {
+        let mut result = vec![];
+        self.place(0, free, [Position::from_u8(0); MAX_PIECES], &mut result);
+        result
+    }
+
+    fn place(self, i: usize, free: u64, mut pieces: [Position; MAX_PIECES], result: &mut Vec<[Position; MAX_PIECES]>) {
+        if i == self.count() {
+            result.push(pieces);
+            return;
+        }
+        let lowest = if i > 0 && self.kind(i - 1) == self.kind(i) { pieces[i - 1].to_u8() + 1 } else { 0 };
+        for square in (lowest..64).filter(|square| free >> square & 1 == 1) {
+            pieces[i] = Position::from_u8(square);
+            self.place(i + 1, free & !(1 << square), pieces, result);
+        }
+    }
+
+    // what is left after black captured a piece of the given kind
+    pub fn without(self, piece: Piece) -> Self {
+        assert!(self.counts[piece as usize] > 0);
+        let mut counts = self.counts;
+        counts[piece as usize] -= 1;
+        Material { counts }
+    }
+
+    // whether black can reach the other material by capturing pieces
+    pub fn contains(self, other: Material) -> bool {
+        self.counts.iter().zip(&other.counts).all(|(count, other)| count >= other)
+    }
+
+    // the materials black can reach by capturing a piece
+    pub fn captures(self) -> Vec<Self> {
+        PIECES.iter().filter(|piece| self.counts[**piece as usize] > 0).map(|piece| self.without(*piece)).collect()
+    }
+
+    // the captures that have a tablebase, without any pieces white can't mate anymore
+    pub fn capture_tables(self) -> Vec<Self> {
+        self.captures().into_iter().filter(|captured| captured.count() > 0).collect()
+    }
+}
+
+// the strongest pieces come first, like in KBNvK
+impl fmt::Display for Material {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "K")?;
+        for piece in PIECES.iter().rev() {
+            for _ in 0..self.counts[*piece as usize] {
+                write!(f, "{}", piece.to_char())?;
+            }
+        }
+        write!(f, "vK")
+    }
+}
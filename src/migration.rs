@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::path::Path;
+use memmap::MmapOptions;
+use rayon::prelude::*;
+use crate::captures::Captures;
+use crate::compression::CompressedTable;
+use crate::encoding::{IndexScheme, Mode, SLICE_COUNT, TARGET_COUNT, slice_offset, slice_size};
+use crate::header::{Header, Layout};
+use crate::material::Material;
+use crate::partition::Partitions;
+use crate::state::{Position, State};
+use crate::tablebase::TableData;
+
+// the rotational index scheme has the white king anywhere in the bottom left quadrant
+const ROTATIONAL_STATE_COUNT: usize = 16 * 63 * 28 * 2 * 37820;
+const ROTATIONAL_BLOCK_SIZE: usize = 16 * 63 * 61;
+// the reflected index scheme has the white king in the triangle, but reserves an index for every placement of the pieces
+const REFLECTED_STATE_COUNT: usize = 10 * 63 * 28 * 2 * 37820;
+const REFLECTED_BLOCK_SIZE: usize = 10 * 63 * 122;
+
+// The triangle lies in the quadrant, so a state normalized for the reflected scheme is also normalized for the rotational one
+// and the encodings only differ in the least significant digit, the white king.
+fn rotational_index(reflected: usize) -> usize {
+    Position::from_u8_triangle((reflected % 10) as u8).to_u8_bottom_left() as usize + 16 * (reflected / 10)
+}
+
+fn old_index(index: usize, scheme: IndexScheme) -> usize {
+    match scheme {
+        IndexScheme::Rotational => rotational_index(State::unpack(index as u64, Mode::Target, Material::THREE_KNIGHTS, IndexScheme::Dense).pack_reflected() as usize),
+        IndexScheme::Reflected => State::unpack(index as u64, Mode::Target, Material::THREE_KNIGHTS, IndexScheme::Dense).pack_reflected() as usize,
+        IndexScheme::Dense | IndexScheme::Interleaved => panic!("Tablebases with the current index schemes don't have to be migrated!"),
+    }
+}
+
+// files written before the header was introduced are just the raw dp bytes in the rotational scheme
+fn read_old(file: &File, legacy: bool) -> Result<(TableData, usize, IndexScheme), String> {
+    let mmap = unsafe { MmapOptions::new().map(file) }.map_err(|e| format!("Could not map the tablebase: {}", e))?;
+    if legacy {
+        if mmap.len() != ROTATIONAL_STATE_COUNT {
+            return Err(String::from("Tablebase has the wrong size!"));
+        }
+        return Ok((TableData::Mapped(mmap, 0), 1, IndexScheme::Rotational));
+    }
+    let header = Header::read(&mut &mmap[..])?;
+    let (state_count, block_size) = match header.index_scheme {
+        IndexScheme::Rotational => (ROTATIONAL_STATE_COUNT, ROTATIONAL_BLOCK_SIZE),
+        IndexScheme::Reflected => (REFLECTED_STATE_COUNT, REFLECTED_BLOCK_SIZE),
+        IndexScheme::Dense | IndexScheme::Interleaved => return Err(String::from("Tablebase already uses the current index scheme!")),
+    };
+    if header.material != Material::THREE_KNIGHTS {
+        return Err(format!("Tablebase is for material {}, but older versions only generated {}!", header.material, Material::THREE_KNIGHTS));
+    }
+    header.check_contents(state_count)?;
+    if header.offset != 0 || header.entries != state_count as u64 {
+        return Err(String::from("Tablebase file only contains a slice, migrate the tablebase it was split from instead!"));
+    }
+    let width = header.value_width as usize;
+    let offset = header.len();
+    let dp = match header.layout {
+        Layout::Raw => {
+            if mmap.len() != offset + state_count * width {
+                return Err(String::from("Tablebase has the wrong size!"));
+            }
+            TableData::Mapped(mmap, offset)
+        }
+        Layout::Compressed => TableData::Owned(CompressedTable::open(mmap, offset, state_count * width, block_size)?.decompress()),
+        Layout::Bitmap => return Err(String::from("Win/draw bitmaps can't be migrated, migrate the tablebase they were built from and run wdl again!")),
+    };
+    header.verify_checksum(&dp.raw())?;
+    Ok((dp, width, header.index_scheme))
+}
+
+// Old tablebases only have the target fields on the rim, so they are converted into a partitioned tablebase
+// that is missing the slices of the other targets.
+pub fn migrate(file: &File, legacy: bool, directory: &Path) -> Result<(), String> {
+    println!("Reading old tablebase...");
+    let (old, width, scheme) = read_old(file, legacy)?;
+    let old = old.raw();
+    println!("Converting from the {:?} to the {:?} index scheme...", scheme, IndexScheme::Dense);
+    std::fs::create_dir_all(directory).map_err(|e| format!("Could not create {}: {}", directory.display(), e))?;
+    for slice in (0..SLICE_COUNT).filter(|slice| Position::from_u8_target((slice % TARGET_COUNT) as u8).is_on_rim()) {
+        let mut dp = vec![0u8; slice_size(slice, Material::THREE_KNIGHTS) * width];
+        dp.par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(index, value)| {
+                let old_index = old_index(slice_offset(slice, Material::THREE_KNIGHTS) + index, scheme);
+                value.copy_from_slice(&old[old_index * width..(old_index + 1) * width]);
+            });
+        Partitions::write_slice(directory, slice, &dp, width, Material::THREE_KNIGHTS, IndexScheme::Dense, 0);
+    }
+    // opening the tablebase only maps the tables after a capture, so they are generated here like gen does
+    Captures::generate(rayon::current_num_threads(), Material::THREE_KNIGHTS, Mode::Target)?.write(directory)
+}
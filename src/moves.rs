@@ -1,4 +1,5 @@
 use crate::state::{Position, State};
+use crate::material::Piece;
 use std::ops;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -97,6 +98,95 @@ fn add_if_not_out_of_bounds(m: PossibleMoves, pos: Position, dx: i16, dy: i16) -
     }
 }
 
+// the directions of the sliders
+const BISHOP_DIRECTIONS: [(i16, i16); 4] = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+const ROOK_DIRECTIONS: [(i16, i16); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+// walks along the directions until the board ends or a square is occupied, which is included
+fn slide(pos: Position, directions: &[(i16, i16)], occupied: u64) -> u64 {
+    let mut m = 0;
+    for (dx, dy) in directions {
+        let mut square = pos;
+        while !square.is_out_of_bounds(*dx, *dy) {
+            square = square.add(*dx, *dy);
+            m |= 1 << square.to_u8();
+            if occupied >> square.to_u8() & 1 == 1 {
+                break;
+            }
+        }
+    }
+    m
+}
+
+// The attacks of a slider on one square for every occupancy of the squares that can block it. The occupancy is hashed by
+// multiplying it with the magic number, the highest bits of the product are the index into the attacks.
+struct Magic {
+    // the squares between the slider and the edge of the board, the last square of a line never blocks anything
+    mask: u64,
+    magic: u64,
+    shift: u32,
+    attacks: Vec<PossibleMoves>,
+}
+
+impl Magic {
+    fn index(&self, occupied: u64) -> usize {
+        ((occupied & self.mask).wrapping_mul(self.magic) >> self.shift) as usize
+    }
+
+    // Tries random numbers with few bits set until one maps every occupancy to an index without a collision
+    // of different attacks. The seed is fixed, so the same magics are found every time.
+    fn find(pos: Position, directions: &[(i16, i16)], seed: &mut u64) -> Self {
+        let mask = slide(pos, directions, 0) & !directions.iter().fold(0, |edges, (dx, dy)| {
+            let mut square = pos;
+            while !square.is_out_of_bounds(*dx, *dy) {
+                square = square.add(*dx, *dy);
+            }
+            if square == pos { edges } else { edges | 1 << square.to_u8() }
+        });
+        let bits = mask.count_ones();
+        // every subset of the mask, see the carry-rippler trick
+        let mut occupancies = vec![];
+        let mut subset = 0u64;
+        loop {
+            occupancies.push((subset, slide(pos, directions, subset)));
+            subset = subset.wrapping_sub(mask) & mask;
+            if subset == 0 {
+                break;
+            }
+        }
+        let mut random = || {
+            // xorshift64*
+            *seed ^= *seed >> 12;
+            *seed ^= *seed << 25;
+            *seed ^= *seed >> 27;
+            seed.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        };
+        loop {
+            let magic = random() & random() & random();
+            let mut magic = Magic { mask, magic, shift: 64 - bits, attacks: vec![PossibleMoves::empty(); 1 << bits] };
+            let mut used = vec![false; 1 << bits];
+            let mut collision = false;
+            for (occupied, attacks) in &occupancies {
+                let index = magic.index(*occupied);
+                if used[index] && magic.attacks[index].0 != *attacks {
+                    collision = true;
+                    break;
+                }
+                used[index] = true;
+                magic.attacks[index] = PossibleMoves(*attacks);
+            }
+            if !collision {
+                return magic;
+            }
+        }
+    }
+}
+
+fn find_magics(directions: &[(i16, i16)]) -> Vec<Magic> {
+    let mut seed = 0x3a9c_5f1d_26e4_b807;
+    (0..64).map(|i| Magic::find(Position::from_u8(i), directions, &mut seed)).collect()
+}
+
 lazy_static! {
     static ref knight_moves: Vec<PossibleMoves> = {
         let mut moves = Vec::new();
@@ -131,6 +221,20 @@ lazy_static! {
         }
         moves
     };
+    // the squares a pawn attacks, for black and for white pawns
+    static ref pawn_attacks: [Vec<PossibleMoves>; 2] = {
+        let mut attacks = [Vec::new(), Vec::new()];
+        for (white, attacks) in attacks.iter_mut().enumerate() {
+            let dy = if white == 1 { 1 } else { -1 };
+            for i in 0..64 {
+                let pos = Position::from_u8(i);
+                attacks.push(add_if_not_out_of_bounds(add_if_not_out_of_bounds(PossibleMoves::empty(), pos, -1, dy), pos, 1, dy));
+            }
+        }
+        attacks
+    };
+    static ref bishop_magics: Vec<Magic> = find_magics(&BISHOP_DIRECTIONS);
+    static ref rook_magics: Vec<Magic> = find_magics(&ROOK_DIRECTIONS);
 }
 
 impl Position {
@@ -140,6 +244,49 @@ impl Position {
     pub fn knight_moves(&self) -> PossibleMoves {
         knight_moves[self.to_u8() as usize]
     }
+
+    // the attacks of the sliders end on the first occupied square in every direction, which is included
+    pub fn bishop_moves(&self, occupied: PossibleMoves) -> PossibleMoves {
+        let magic = &bishop_magics[self.to_u8() as usize];
+        magic.attacks[magic.index(occupied.0)]
+    }
+    pub fn rook_moves(&self, occupied: PossibleMoves) -> PossibleMoves {
+        let magic = &rook_magics[self.to_u8() as usize];
+        magic.attacks[magic.index(occupied.0)]
+    }
+    pub fn queen_moves(&self, occupied: PossibleMoves) -> PossibleMoves {
+        self.bishop_moves(occupied) | self.rook_moves(occupied)
+    }
+
+    // the squares a pawn of the given color captures on
+    pub fn pawn_attacks(&self, white: bool) -> PossibleMoves {
+        pawn_attacks[white as usize][self.to_u8() as usize]
+    }
+
+    // the squares a pawn of the given color can move to without capturing, two squares from its starting rank if both are empty
+    pub fn pawn_pushes(&self, white: bool, occupied: PossibleMoves) -> PossibleMoves {
+        let (dy, start) = if white { (1, 1) } else { (-1, 6) };
+        if self.is_out_of_bounds(0, dy) || occupied.contains(self.add(0, dy)) {
+            return PossibleMoves::empty();
+        }
+        let single = PossibleMoves::from_position(self.add(0, dy));
+        if self.y == start && !occupied.contains(self.add(0, 2 * dy)) {
+            single | PossibleMoves::from_position(self.add(0, 2 * dy))
+        }
+        else {
+            single
+        }
+    }
+
+    // the squares a piece on this square attacks, which are also the ones it can move to unless they are occupied
+    pub fn attacks(&self, piece: Piece, occupied: PossibleMoves) -> PossibleMoves {
+        match piece {
+            Piece::Knight => self.knight_moves(),
+            Piece::Bishop => self.bishop_moves(occupied),
+            Piece::Rook => self.rook_moves(occupied),
+            Piece::Queen => self.queen_moves(occupied),
+        }
+    }
 }
 
 impl State {
@@ -147,57 +294,84 @@ impl State {
         ((self.black_king.king_moves() | PossibleMoves::from_position(self.black_king)) / self.covered_by_white()) == PossibleMoves::empty()
     }
 
+    // black is to move, but the king isn't in check and can't go anywhere
+    pub fn is_stalemate(&self) -> bool {
+        !self.black_in_check() && (self.black_king.king_moves() / self.covered_by_white()) == PossibleMoves::empty()
+    }
+
     pub fn black_in_check(&self) -> bool {
         self.covered_by_white().contains(self.black_king)
     }
 
+    // the positions that have an index, see DenseState
+    pub fn is_legal(&self) -> bool {
+        !self.white_king.king_moves().contains(self.black_king) && !(self.white_to_move && self.black_in_check())
+    }
+
+    // The black king doesn't block the sliders, as it can't step back along their lines.
+    // The squares of the white pieces are covered if another piece protects them.
     pub fn covered_by_white(&self) -> PossibleMoves {
-        self.white_king.king_moves() | self.knights[0].knight_moves() | self.knights[1].knight_moves() | self.knights[2].knight_moves()
+        let occupied = self.white_pieces();
+        self.pieces().iter().enumerate().fold(self.white_king.king_moves(), |covered, (i, pos)| covered | pos.attacks(self.material.kind(i), occupied))
     }
 
-    pub fn white_knights(&self) -> PossibleMoves {
-        PossibleMoves::from_position(self.knights[0]) | PossibleMoves::from_position(self.knights[1]) | PossibleMoves::from_position(self.knights[2])
+    // the white pieces besides the king
+    pub fn piece_squares(&self) -> PossibleMoves {
+        self.pieces().iter().fold(PossibleMoves::empty(), |squares, pos| squares | PossibleMoves::from_position(*pos))
     }
 
     pub fn white_pieces(&self) -> PossibleMoves {
-        PossibleMoves::from_position(self.white_king) | self.white_knights()
+        PossibleMoves::from_position(self.white_king) | self.piece_squares()
     }
 
-    pub fn pieces(&self) -> PossibleMoves {
+    pub fn occupied(&self) -> PossibleMoves {
         self.white_pieces() | PossibleMoves::from_position(self.black_king)
     }
 
+    // the pieces the black king can take because they aren't protected
+    pub fn captures(&self) -> PossibleMoves {
+        (self.piece_squares() & self.black_king.king_moves()) / self.covered_by_white()
+    }
+
+    // the position after the black king took the piece on captured, with the material that is left
+    pub fn after_capture(&self, captured: Position) -> Self {
+        let i = self.pieces().iter().position(|pos| *pos == captured).unwrap();
+        let mut pieces = self.pieces;
+        for j in i..self.material.count() - 1 {
+            pieces[j] = pieces[j + 1];
+        }
+        State { material: self.material.without(self.material.kind(i)), pieces, black_king: captured, white_to_move: true, ..*self }
+    }
+
+    // the squares the i-th piece can move to, which are also the ones it can come from
+    fn piece_moves(&self, i: usize) -> PossibleMoves {
+        self.pieces[i].attacks(self.material.kind(i), self.occupied()) / self.occupied()
+    }
+
+    fn with_piece(&self, i: usize, pos: Position) -> Self {
+        let mut pieces = self.pieces;
+        pieces[i] = pos;
+        State { pieces, ..*self }
+    }
+
     pub fn next_states(&self) -> Vec<Self> {
         let mut result = Vec::new();
 
         if self.white_to_move {
-            for pos in (self.white_king.king_moves() / (self.pieces() | self.black_king.king_moves())).iter() {
+            for pos in (self.white_king.king_moves() / (self.occupied() | self.black_king.king_moves())).iter() {
                 result.push(State {
                     white_king: pos,
                     white_to_move: false,
                     ..*self
                 })
             }
-            for pos in (self.knights[0].knight_moves() / (self.pieces())).iter() {
-                result.push(State {
-                    knights: [pos, self.knights[1], self.knights[2]],
-                    white_to_move: false,
-                    ..*self
-                })
-            }
-            for pos in (self.knights[1].knight_moves() / (self.pieces())).iter() {
-                result.push(State {
-                    knights: [self.knights[0], pos, self.knights[2]],
-                    white_to_move: false,
-                    ..*self
-                })
-            }
-            for pos in (self.knights[2].knight_moves() / (self.pieces())).iter() {
-                result.push(State {
-                    knights: [self.knights[0], self.knights[1], pos],
-                    white_to_move: false,
-                    ..*self
-                })
+            for i in 0..self.material.count() {
+                for pos in self.piece_moves(i).iter() {
+                    result.push(State {
+                        white_to_move: false,
+                        ..self.with_piece(i, pos)
+                    })
+                }
             }
         }
         else {
@@ -213,15 +387,19 @@ impl State {
         result
     }
 
+    // the moves that don't capture a piece
     pub fn next_states_count(&self) -> u8 {
         if self.white_to_move {
-            let mut res = 0;
-            res += (self.white_king.king_moves() / (self.pieces() | self.black_king.king_moves())).count();
-            res += (self.knights[0].knight_moves() / (self.pieces())).count();
-            res += (self.knights[1].knight_moves() / (self.pieces())).count();
-            res += (self.knights[2].knight_moves() / (self.pieces())).count();
+            let mut res = (self.white_king.king_moves() / (self.occupied() | self.black_king.king_moves())).count();
+            for i in 0..self.material.count() {
+                res += self.piece_moves(i).count();
+            }
             res
         }
+        else if self.is_symmetric() {
+            // the moves to mirrored squares lead to the same index, so they are only counted once
+            (self.black_king.king_moves() / (self.white_pieces() | self.covered_by_white())).iter().filter(|pos| pos.y <= pos.x).count() as u8
+        }
         else {
             (self.black_king.king_moves() / (self.white_pieces() | self.covered_by_white())).count()
         }
@@ -232,7 +410,7 @@ impl State {
 
         if self.white_to_move {
             assert_eq!(self.black_in_check(), false);
-            for pos in (self.black_king.king_moves() / (self.pieces() | self.white_king.king_moves())).iter() {
+            for pos in (self.black_king.king_moves() / (self.occupied() | self.white_king.king_moves())).iter() {
                 result.push(State {
                     black_king: pos,
                     white_to_move: false,
@@ -241,48 +419,65 @@ impl State {
             }
         }
         else {
-            for pos in (self.white_king.king_moves() / (self.pieces() | self.black_king.king_moves())).iter() {
-                let state = State {
-                    white_king: pos,
-                    white_to_move: true,
-                    ..*self
-                };
-                if !state.black_in_check() {
-                    result.push(state)
-                }
+            let mut moved = vec![];
+            for pos in (self.white_king.king_moves() / (self.occupied() | self.black_king.king_moves())).iter() {
+                moved.push(State { white_king: pos, ..*self });
             }
-            for pos in (self.knights[0].knight_moves() / self.pieces()).iter() {
-                let state = State {
-                    knights: [pos, self.knights[1], self.knights[2]],
-                    white_to_move: true,
-                    ..*self
-                };
-                if !state.black_in_check() {
-                    result.push(state)
+            for i in 0..self.material.count() {
+                for pos in self.piece_moves(i).iter() {
+                    moved.push(self.with_piece(i, pos));
                 }
             }
-            for pos in (self.knights[1].knight_moves() / self.pieces()).iter() {
-                let state = State {
-                    knights: [self.knights[0], pos, self.knights[2]],
-                    white_to_move: true,
-                    ..*self
-                };
+            for state in moved {
+                let state = State { white_to_move: true, ..state };
                 if !state.black_in_check() {
                     result.push(state)
                 }
             }
-            for pos in (self.knights[2].knight_moves() / self.pieces()).iter() {
-                let state = State {
-                    knights: [self.knights[0], self.knights[1], pos],
-                    white_to_move: true,
-                    ..*self
-                };
-                if !state.black_in_check() {
-                    result.push(state)
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The magics against slide for every occupancy of the mask, the squares outside of it must not change the attacks.
+    fn check_magics(magics: &[Magic], directions: &[(i16, i16)], attacks: fn(&Position, PossibleMoves) -> PossibleMoves) {
+        for (i, magic) in magics.iter().enumerate() {
+            let pos = Position::from_u8(i as u8);
+            let mut subset = 0u64;
+            loop {
+                assert_eq!(attacks(&pos, PossibleMoves(subset)).0, slide(pos, directions, subset), "{} with {:x}", pos, subset);
+                assert_eq!(attacks(&pos, PossibleMoves(subset | !magic.mask)).0, slide(pos, directions, subset | !magic.mask), "{} with {:x}", pos, subset | !magic.mask);
+                subset = subset.wrapping_sub(magic.mask) & magic.mask;
+                if subset == 0 {
+                    break;
                 }
             }
         }
+    }
 
-        result
+    #[test]
+    fn magics_match_slide() {
+        check_magics(&bishop_magics, &BISHOP_DIRECTIONS, Position::bishop_moves);
+        check_magics(&rook_magics, &ROOK_DIRECTIONS, Position::rook_moves);
+    }
+
+    // pawns only stand between the second and the seventh rank, with every square blocked alone, nothing and everything
+    #[test]
+    fn pawns_match_chess() {
+        for square in chess::ALL_SQUARES.iter().filter(|square| (1..7).contains(&square.get_rank().to_index())) {
+            let pos = Position::from_u8(square.to_index() as u8);
+            for (white, color) in &[(true, chess::Color::White), (false, chess::Color::Black)] {
+                assert_eq!(pos.pawn_attacks(*white).0, chess::get_pawn_attacks(*square, *color, !chess::EMPTY).0, "{}", pos);
+                for occupied in (0..64).map(|i| 1u64 << i).chain(vec![0, !0]) {
+                    let moves = pos.pawn_attacks(*white).0 & occupied | pos.pawn_pushes(*white, PossibleMoves(occupied)).0;
+                    assert_eq!(moves, chess::get_pawn_moves(*square, *color, chess::BitBoard(occupied)).0, "{} with {:x}", pos, occupied);
+                }
+            }
+        }
     }
 }
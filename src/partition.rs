@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::encoding::{IndexScheme, Mode, SLICE_COUNT, TARGET_COUNT, slice_of, slice_offset, slice_size};
+use crate::material::Material;
+use crate::header::{Header, Layout, checksum};
+use crate::state::Position;
+use crate::tablebase::{TableData, write_table};
+use crate::target::Target;
+
+pub fn slice_file_name(slice: usize) -> String {
+    format!("{}_{}.tb", Position::from_u8_target((slice % TARGET_COUNT) as u8), if slice / TARGET_COUNT == 1 { "w" } else { "b" })
+}
+
+// State::normalize rotates and mirrors the target field together with the white king, so every image of the target is needed
+fn target_slices(target: Position) -> Vec<usize> {
+    let mut result = vec![];
+    for rotated in &[target, target.rotate_clockwise(), target.rotate_counterclockwise(), target.rotate_twice()] {
+        for image in &[*rotated, rotated.mirror_diagonal()] {
+            for white_to_move in 0..2 {
+                let slice = image.to_u8_target() as usize + TARGET_COUNT * white_to_move;
+                if !result.contains(&slice) {
+                    result.push(slice);
+                }
+            }
+        }
+    }
+    result
+}
+
+pub struct Partitions {
+    directory: PathBuf,
+    in_memory: bool,
+    // the material, the bytes per state and the index scheme are the same for all slices
+    material: Material,
+    width: usize,
+    scheme: IndexScheme,
+    // see Header::max_depth, also the same for all slices
+    max_depth: u16,
+    slices: Vec<RwLock<Option<TableData>>>,
+    // slices that may be loaded, either because their file exists or because they were requested
+    available: Vec<AtomicBool>,
+    // held while missing targets are generated, so each of them is only generated once
+    generating: Mutex<()>,
+}
+
+impl Partitions {
+    // writes the slices of every target field the mode has, a tablebase of Mode::Fields only has some of them
+    pub fn write(directory: &Path, dp: &[u8], width: usize, material: Material, mode: Mode, scheme: IndexScheme, max_depth: u16) {
+        std::fs::create_dir_all(directory).unwrap();
+        let targets = mode.target_count();
+        let (black_size, white_size) = (material.slice_size(0), material.slice_size(1));
+        for index in 0..targets {
+            let field = match mode.target(index) {
+                Target::Field(field) => field.to_u8_target() as usize,
+                _ => panic!("Only tablebases with target fields can be partitioned!"),
+            };
+            let black = index * black_size;
+            let white = targets * black_size + index * white_size;
+            Partitions::write_slice(directory, field, &dp[black * width..(black + black_size) * width], width, material, scheme, max_depth);
+            Partitions::write_slice(directory, field + TARGET_COUNT, &dp[white * width..(white + white_size) * width], width, material, scheme, max_depth);
+        }
+    }
+
+    // the slices of Mode::Fields are the ones of Mode::Target for their fields, so they are written and read as such
+    pub fn write_slice(directory: &Path, slice: usize, chunk: &[u8], width: usize, material: Material, scheme: IndexScheme, max_depth: u16) {
+        let header = Header { offset: slice_offset(slice, material) as u64, entries: slice_size(slice, material) as u64, max_depth, index_scheme: scheme, ..Header::new(material, Mode::Target, width as u8, checksum(chunk)) };
+        write_table(File::create(directory.join(slice_file_name(slice))).unwrap(), chunk, header);
+    }
+
+    pub fn open(directory: &Path, in_memory: bool, targets: Option<&[Position]>) -> Result<Self, String> {
+        let available = match targets {
+            None => (0..SLICE_COUNT).map(|slice| directory.join(slice_file_name(slice)).is_file()).collect(),
+            Some(targets) => {
+                let mut available = vec![false; SLICE_COUNT];
+                for target in targets {
+                    for slice in target_slices(*target) {
+                        available[slice] = true;
+                    }
+                }
+                available
+            }
+        };
+        // the material, width, scheme and max depth are taken from any slice, load checks that the others agree
+        let (material, width, scheme, max_depth) = match (0..SLICE_COUNT).find(|slice| available[*slice] && directory.join(slice_file_name(*slice)).is_file()) {
+            Some(slice) => {
+                let path = directory.join(slice_file_name(slice));
+                let file = File::open(&path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+                let header = Header::read(&mut BufReader::new(file))?;
+                (header.material, header.value_width as usize, header.index_scheme, header.max_depth)
+            }
+            None => return Err(format!("{} contains no slices of a tablebase!", directory.display())),
+        };
+        let partitions = Partitions {
+            directory: directory.to_path_buf(),
+            in_memory,
+            material,
+            width,
+            scheme,
+            max_depth,
+            slices: (0..SLICE_COUNT).map(|_| RwLock::new(None)).collect(),
+            available: available.into_iter().map(AtomicBool::new).collect(),
+            generating: Mutex::new(()),
+        };
+        if targets.is_some() {
+            for slice in 0..SLICE_COUNT {
+                if partitions.is_available(slice) {
+                    *partitions.slices[slice].write().unwrap() = Some(partitions.load(slice)?);
+                }
+            }
+        }
+        Ok(partitions)
+    }
+
+    fn load(&self, slice: usize) -> Result<TableData, String> {
+        let path = self.directory.join(slice_file_name(slice));
+        println!("Loading {}...", path.display());
+        let file = File::open(&path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        let (dp, header) = if self.in_memory { TableData::read(&file)? } else { TableData::map(&file)? };
+        if header.mode != Mode::Target {
+            return Err(format!("{} has no target field, only tablebases with target fields can be partitioned!", path.display()));
+        }
+        if header.layout == Layout::Bitmap {
+            return Err(format!("{} is a win/draw bitmap, partitioned tablebases have to contain distances!", path.display()));
+        }
+        if header.material != self.material {
+            return Err(format!("{} is for material {}, but the other slices are for {}!", path.display(), header.material, self.material));
+        }
+        if header.offset != slice_offset(slice, self.material) as u64 || header.entries != slice_size(slice, self.material) as u64 {
+            return Err(format!("{} does not contain the slice it is named after!", path.display()));
+        }
+        if header.value_width as usize != self.width {
+            return Err(format!("{} stores {} bytes per state, but the other slices store {}!", path.display(), header.value_width, self.width));
+        }
+        if header.index_scheme != self.scheme {
+            return Err(format!("{} uses the {:?} index scheme, but the other slices use {:?}!", path.display(), header.index_scheme, self.scheme));
+        }
+        if header.max_depth != self.max_depth {
+            return Err(format!("{} was generated up to a depth of {}, but the other slices up to {} (0 means completely)!", path.display(), header.max_depth, self.max_depth));
+        }
+        Ok(dp)
+    }
+
+    // loads the slice on first use, which fails if its file is missing or broken
+    fn with_slice<T, F>(&self, slice: usize, f: F) -> Result<T, String> where F: FnOnce(&TableData) -> T {
+        if let Some(dp) = &*self.slices[slice].read().unwrap() {
+            return Ok(f(dp));
+        }
+        if !self.is_available(slice) {
+            return Err(format!("{} is not available!", slice_file_name(slice)));
+        }
+        let mut dp = self.slices[slice].write().unwrap();
+        if dp.is_none() {
+            *dp = Some(self.load(slice)?);
+        }
+        Ok(f(dp.as_ref().unwrap()))
+    }
+
+    pub fn material(&self) -> Material {
+        self.material
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn scheme(&self) -> IndexScheme {
+        self.scheme
+    }
+
+    pub fn max_depth(&self) -> u16 {
+        self.max_depth
+    }
+
+    pub fn get(&self, index: usize) -> Result<u16, String> {
+        let slice = slice_of(index, self.material);
+        self.with_slice(slice, |dp| dp.value(index - slice_offset(slice, self.material), self.width))
+    }
+
+    fn is_available(&self, slice: usize) -> bool {
+        self.available[slice].load(Ordering::SeqCst)
+    }
+
+    pub fn has_target(&self, target: Position) -> bool {
+        target_slices(target).iter().all(|slice| self.is_available(*slice))
+    }
+
+    // Calls generate for the target fields whose slices are missing and writes them into the directory.
+    // generate returns the table of the mode it is given, with the material, width and max depth of the other slices.
+    pub fn add_target<F>(&self, target: Position, generate: F) -> Result<(), String> where F: FnOnce(Mode, usize, u16) -> Result<Vec<u8>, String> {
+        let _generating = self.generating.lock().unwrap();
+        if self.has_target(target) {
+            return Ok(());
+        }
+        let mode = Mode::from_fields(&[target]);
+        let dp = generate(mode, self.width, self.max_depth)?;
+        Partitions::write(&self.directory, &dp, self.width, self.material, mode, self.scheme, self.max_depth);
+        for slice in target_slices(target) {
+            self.available[slice].store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    pub fn raw(&self) -> Result<Vec<u8>, String> {
+        let mut dp = Vec::with_capacity(Mode::Target.state_count(self.material) * self.width);
+        for slice in 0..SLICE_COUNT {
+            self.with_slice(slice, |slice| dp.extend_from_slice(&slice.raw()))?;
+        }
+        Ok(dp)
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Duration;
+use crate::encoding::Mode;
+use crate::material::Material;
+use crate::search::{Dp, DtmCell, WIDE_DRAW, WIDE_NOT_CALCULATED};
+use crate::tablebase::Tablebase;
+use crate::target::Target;
+
+#[derive(Serialize)]
+struct LayerReport {
+    layer: u16,
+    // the side to move in the positions found in this layer
+    side_to_move: &'static str,
+    new_positions: usize,
+    seconds: f64,
+    // the crc32 of the dp array after this layer, continued from the one of the layer before
+    hash: String,
+}
+
+// What gen --report writes, the hashes don't depend on the number of threads, so two runs can be compared to find regressions.
+#[derive(Serialize)]
+pub struct Report {
+    material: String,
+    mode: String,
+    threads: usize,
+    layers: Vec<LayerReport>,
+    // the longest mate of every target, None if white can't win with it, empty until finish
+    max_dtm: BTreeMap<String, Option<u16>>,
+    #[serde(skip)]
+    hash: u32,
+}
+
+fn target_name(target: Target) -> String {
+    match target {
+        Target::Field(field) => field.to_string(),
+        Target::Region(_) => target.squares().iter().map(|square| square.to_string()).collect::<Vec<String>>().join(","),
+        Target::Anywhere => String::from("anywhere"),
+    }
+}
+
+impl Report {
+    pub fn new(threads: usize, material: Material, mode: Mode) -> Self {
+        Report { material: material.to_string(), mode: format!("{:?}", mode), threads, layers: vec![], max_dtm: BTreeMap::new(), hash: 0 }
+    }
+
+    // called after the positions of the layer are stored in dp, the mates are layer 0
+    pub fn add_layer<C: DtmCell>(&mut self, dp: &Dp<C>, layer: u16, white_to_move: bool, new_positions: usize, time: Duration) {
+        self.add_hashed_layer(|hash| dp.hash(hash), layer, white_to_move, new_positions, time);
+    }
+
+    // like add_layer for a dp array that isn't in memory, hash continues the given hash with its cells like Dp::hash
+    pub fn add_hashed_layer<H: FnOnce(u32) -> u32>(&mut self, hash: H, layer: u16, white_to_move: bool, new_positions: usize, time: Duration) {
+        self.hash = hash(self.hash);
+        self.layers.push(LayerReport {
+            layer,
+            side_to_move: if white_to_move { "white" } else { "black" },
+            new_positions,
+            seconds: time.as_secs_f64(),
+            hash: format!("{:08x}", self.hash),
+        });
+    }
+
+    // the longest mate of every target of the generated table, positions that are draws or not calculated are skipped
+    pub fn finish(&mut self, tb: &Tablebase) {
+        let (mode, material) = (tb.mode(), tb.material());
+        let targets = mode.target_count();
+        let (black_size, white_size) = (material.slice_size(0), material.slice_size(1));
+        for index in 0..targets {
+            let black = index * black_size..(index + 1) * black_size;
+            let white = targets * black_size + index * white_size..targets * black_size + (index + 1) * white_size;
+            let longest = black.chain(white)
+                .map(|i| tb.get(i))
+                .filter(|value| *value != WIDE_DRAW && *value != WIDE_NOT_CALCULATED)
+                .max();
+            self.max_dtm.insert(target_name(mode.target(index)), longest);
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+}
@@ -1,70 +1,406 @@
 use crate::state::{Position, State};
-use crate::tablebase::Tablebase;
-use std::sync::atomic::AtomicI8;
+use crate::tablebase::{Tablebase, TableData};
 use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
-use crossbeam::channel;
 use std::sync::atomic::Ordering;
-use crossbeam::channel::Sender;
 use rayon::ThreadPool;
 use rayon::scope;
-
-pub enum Message {
-    End,
-    Calculate(State)
-}
+use std::path::Path;
+use crate::checkpoint::{Checkpoint, write_checkpoint};
+use crate::encoding::{IndexScheme, Mode};
+use crate::target::Target;
+use crate::captures::Captures;
+use crate::material::Material;
+use std::sync::Mutex;
+use std::cmp::min;
+use std::mem::swap;
+use std::time::Instant;
+use crate::report::Report;
 
 pub const NOT_CALCULATED: u8 = 254;
 pub const DRAW: u8 = 255;
+// sentinels of wide tables, which store two bytes per state once mates get longer than 253 halfmoves
+pub const WIDE_NOT_CALCULATED: u16 = 65534;
+pub const WIDE_DRAW: u16 = 65535;
 
-pub fn prev_layer_white(dp: &Vec<AtomicU8>, s: State, layer: u8, sender: &Sender<Message>) -> () {
-    for prev in s.previous_states() {
-        let packed = prev.pack() as usize;
-        let value = dp[packed].compare_and_swap(NOT_CALCULATED, layer, Ordering::SeqCst);
-        if value == NOT_CALCULATED {
-            if packed == 762463190 {
-                println!("Reached 762463190 from {} ({:?}, {})", s.to_lichess(), s.target_field, s.pack());
-            }
-            sender.send(Message::Calculate(prev.normalize())).unwrap();
+pub fn widen(value: u8) -> u16 {
+    match value {
+        NOT_CALCULATED => WIDE_NOT_CALCULATED,
+        DRAW => WIDE_DRAW,
+        _ => value as u16,
+    }
+}
+
+pub fn narrow(value: u16) -> u8 {
+    match value {
+        WIDE_NOT_CALCULATED => NOT_CALCULATED,
+        WIDE_DRAW => DRAW,
+        _ => {
+            assert!(value < NOT_CALCULATED as u16);
+            value as u8
         }
     }
 }
 
-pub fn prev_layer_black(dp: &Vec<AtomicU8>, outdeg: &Vec<AtomicI8>, s: State, layer: u8, sender: &Sender<Message>) -> () {
-    'outer: for prev in s.previous_states() {
-        let prev_packed = prev.pack() as usize;
+// the value of a state in a table with the given amount of bytes per state, with the sentinels of wide tables
+pub fn value_at(dp: &[u8], width: usize, index: usize) -> u16 {
+    if width == 2 {
+        u16::from_le_bytes([dp[2 * index], dp[2 * index + 1]])
+    }
+    else {
+        widen(dp[index])
+    }
+}
 
-        if outdeg[prev_packed].load(Ordering::SeqCst) == 0 {
-            println!("\nALAAAAAAAAAAAAAAAAAARM\nState: {:?}, {:?}, {}\nPrevious: {:?}, {:?}, {}\n\n", s.to_lichess(), s, s.pack(), prev.to_lichess(), prev, s.pack());
-            continue
-        }
+// A dp entry during the generation. Values are always passed with the sentinels of wide tables,
+// so the search doesn't have to know how many bytes are stored per state.
+pub trait DtmCell: Send + Sync {
+    const WIDTH: usize;
+    // the longest distance that doesn't collide with the sentinels
+    const MAX_VALUE: u16;
+    // a cell with the stored value, see raw
+    fn from_raw(raw: u16) -> Self;
+    // the stored value, as it is written to disk
+    fn raw(&self) -> u16;
+    fn load_value(&self) -> u16;
+    fn store_value(&self, value: u16);
+    // stores the value if the cell still has the current one, returns the value that was found
+    fn compare_and_swap_value(&self, current: u16, value: u16) -> u16;
+}
 
-        outdeg[prev_packed].compare_and_swap(-1, prev.next_states_count() as i8, Ordering::SeqCst);
-        let outdeg = outdeg[prev_packed].fetch_sub(1, Ordering::SeqCst) - 1;
+impl DtmCell for AtomicU8 {
+    const WIDTH: usize = 1;
+    const MAX_VALUE: u16 = NOT_CALCULATED as u16 - 1;
+    fn from_raw(raw: u16) -> Self {
+        AtomicU8::new(raw as u8)
+    }
+    fn raw(&self) -> u16 {
+        self.load(Ordering::Relaxed) as u16
+    }
+    fn load_value(&self) -> u16 {
+        widen(self.load(Ordering::Relaxed))
+    }
+    fn store_value(&self, value: u16) {
+        self.store(narrow(value), Ordering::Relaxed)
+    }
+    fn compare_and_swap_value(&self, current: u16, value: u16) -> u16 {
+        widen(self.compare_exchange(narrow(current), narrow(value), Ordering::SeqCst, Ordering::SeqCst).unwrap_or_else(|found| found))
+    }
+}
 
-        if outdeg > 0 {
-            continue;
+impl DtmCell for AtomicU16 {
+    const WIDTH: usize = 2;
+    const MAX_VALUE: u16 = WIDE_NOT_CALCULATED - 1;
+    fn from_raw(raw: u16) -> Self {
+        AtomicU16::new(raw)
+    }
+    fn raw(&self) -> u16 {
+        self.load(Ordering::Relaxed)
+    }
+    fn load_value(&self) -> u16 {
+        self.load(Ordering::Relaxed)
+    }
+    fn store_value(&self, value: u16) {
+        self.store(value, Ordering::Relaxed)
+    }
+    fn compare_and_swap_value(&self, current: u16, value: u16) -> u16 {
+        self.compare_exchange(current, value, Ordering::SeqCst, Ordering::SeqCst).unwrap_or_else(|found| found)
+    }
+}
+
+// the odd value a black position has while the given number of its moves is left
+fn counter_value(moves: u8) -> u16 {
+    2 * moves as u16 + 1
+}
+
+// The dp array of the generation. Black is always mated after an even number of halfmoves, so the odd values are free
+// in the black slices. Until a black position is calculated, they count its moves that don't lead to processed positions yet.
+// A count of 0 marks a position that waits for the value of its captures. White positions only have their value.
+pub struct Dp<C> {
+    cells: Vec<C>,
+    // the index of the first cell, only a generation with a memory limit has a part of the table in memory
+    offset: usize,
+    // the black slices come first, see encoding
+    black_count: usize,
+    material: Material,
+    mode: Mode,
+    scheme: IndexScheme,
+}
+
+impl<C: DtmCell> Dp<C> {
+    pub fn new(cells: Vec<C>, material: Material, mode: Mode, scheme: IndexScheme) -> Self {
+        assert_eq!(cells.len(), mode.state_count(material));
+        Dp::chunk(cells, 0, material, mode, scheme)
+    }
+
+    // the cells of the indices from offset on
+    pub fn chunk(cells: Vec<C>, offset: usize, material: Material, mode: Mode, scheme: IndexScheme) -> Self {
+        Dp { cells, offset, black_count: mode.target_count() * material.slice_size(0), material, mode, scheme }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn scheme(&self) -> IndexScheme {
+        self.scheme
+    }
+
+    pub fn index_of(&self, state: &State) -> usize {
+        state.pack_in(self.mode, self.scheme) as usize
+    }
+
+    pub fn state_at(&self, index: usize) -> State {
+        State::unpack(index as u64, self.mode, self.material, self.scheme)
+    }
+
+    fn cell(&self, index: usize) -> &C {
+        &self.cells[index - self.offset]
+    }
+
+    pub fn raw(&self, index: usize) -> u16 {
+        self.cell(index).raw()
+    }
+
+    fn is_counter(&self, index: usize, value: u16) -> bool {
+        index < self.black_count && value % 2 == 1 && value < WIDE_NOT_CALCULATED
+    }
+
+    // the value with the sentinels of wide tables, a position that still counts its moves is not calculated
+    pub fn get(&self, index: usize) -> u16 {
+        let value = self.cell(index).load_value();
+        if self.is_counter(index, value) { WIDE_NOT_CALCULATED } else { value }
+    }
+
+    pub fn set(&self, index: usize, value: u16) {
+        self.cell(index).store_value(value)
+    }
+
+    // returns true if the state was not calculated before
+    pub fn set_if_not_calculated(&self, index: usize, value: u16) -> bool {
+        let mut current = self.cell(index).load_value();
+        while current == WIDE_NOT_CALCULATED || self.is_counter(index, current) {
+            let found = self.cell(index).compare_and_swap_value(current, value);
+            if found == current {
+                return true;
+            }
+            current = found;
         }
+        false
+    }
 
-        for pos in ((prev.white_knights() & prev.black_king.king_moves()) / prev.covered_by_white()).iter() {
-            if pos.to_u8() != 0 || prev.knights[1].to_u8() == 63 || prev.knights[2].to_u8() == 63 || prev.target_field.to_u8() != 0 {
-                dp[prev_packed].store(DRAW, Ordering::Relaxed);
-                continue 'outer
+    // the moves of a black position that are left, None if it was never reached or is calculated
+    pub fn remaining_moves(&self, index: usize) -> Option<u8> {
+        let value = self.cell(index).load_value();
+        if self.is_counter(index, value) { Some((value / 2) as u8) } else { None }
+    }
+
+    pub fn set_remaining_moves(&self, index: usize, moves: u8) {
+        self.cell(index).store_value(counter_value(moves))
+    }
+
+    // Counts down the moves of a black position, it starts with the given number when it's reached the first time.
+    // Returns how many are left, or None if the position is calculated or has no moves left.
+    pub fn take_move(&self, index: usize, moves: u8) -> Option<u8> {
+        let mut current = self.cell(index).load_value();
+        loop {
+            let left = if current == WIDE_NOT_CALCULATED {
+                moves
+            }
+            else if self.is_counter(index, current) && current > counter_value(0) {
+                (current / 2) as u8
             }
-            let dummy = State {
-                black_king: Position::from_u8(0),
-                knights: [prev.knights[1], prev.knights[2], Position::from_u8(63)],
-                white_to_move: true,
-                ..prev
+            else {
+                return None;
             };
-            if dp[dummy.pack() as usize].load(Ordering::Relaxed) != 1 {
-                dp[prev_packed].store(DRAW, Ordering::Relaxed);
-                continue 'outer
+            let found = self.cell(index).compare_and_swap_value(current, counter_value(left - 1));
+            if found == current {
+                return Some(left - 1);
+            }
+            current = found;
+        }
+    }
+
+    // marks a black position that waits for its captures, returns false if it was reached before
+    pub fn mark_waiting(&self, index: usize) -> bool {
+        self.cell(index).compare_and_swap_value(WIDE_NOT_CALCULATED, counter_value(0)) == WIDE_NOT_CALCULATED
+    }
+
+    // the table as it is written to disk, the counters are not calculated
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.cells.len() * C::WIDTH);
+        for index in self.offset..self.offset + self.cells.len() {
+            let value = self.get(index);
+            let raw = if C::WIDTH == 1 { narrow(value) as u16 } else { value };
+            for i in 0..C::WIDTH {
+                bytes.push((raw >> (8 * i)) as u8);
+            }
+        }
+        bytes
+    }
+
+    // Continues the crc32 of the stored values with the cells, the counters included. After a layer the cells
+    // don't depend on the order in which the threads found the positions.
+    pub fn hash(&self, initial: u32) -> u32 {
+        let mut hasher = crc32fast::Hasher::new_with_initial(initial);
+        let mut bytes = Vec::with_capacity((1 << 16) * C::WIDTH);
+        for cells in self.cells.chunks(1 << 16) {
+            bytes.clear();
+            for cell in cells {
+                bytes.extend_from_slice(&cell.raw().to_le_bytes()[..C::WIDTH]);
+            }
+            hasher.update(&bytes);
+        }
+        hasher.finalize()
+    }
+
+    // the values and counters with two bytes per state
+    fn widen(&self) -> Dp<AtomicU16> {
+        Dp { cells: self.cells.iter().map(|cell| AtomicU16::new(cell.load_value())).collect(), offset: self.offset, black_count: self.black_count, material: self.material, mode: self.mode, scheme: self.scheme }
+    }
+}
+
+// generation starts with one byte per state and switches to two bytes if the layers reach the sentinels
+pub enum DpCells {
+    Narrow(Dp<AtomicU8>),
+    Wide(Dp<AtomicU16>),
+}
+
+// the indices are swept in chunks of this many words, each chunk is a task for the thread pool
+const CHUNK_WORDS: usize = 1 << 14;
+
+// The positions of a layer with one bit per index. The workers sweep disjoint ranges of the current layer
+// and mark the positions they find in the next one, so the memory doesn't depend on the size of the layers.
+pub struct Frontier {
+    words: Vec<AtomicU64>,
+}
+
+impl Frontier {
+    pub fn new(len: usize) -> Self {
+        Frontier { words: fill_vec((len + 63) / 64, || AtomicU64::new(0)) }
+    }
+
+    pub fn insert(&self, index: usize) {
+        self.words[index / 64].fetch_or(1 << (index % 64), Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|word| word.load(Ordering::Relaxed).count_ones() as usize).sum()
+    }
+
+    // the indices of the positions in increasing order
+    pub fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(word, bits)| {
+            let bits = bits.load(Ordering::Relaxed);
+            (0..64).filter(move |bit| bits >> bit & 1 == 1).map(move |bit| word * 64 + bit)
+        })
+    }
+
+    fn clear(&self) {
+        for word in &self.words {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // calls f with the index of every position, on the threads of the current pool
+    fn sweep<F: Fn(usize) + Sync>(&self, f: F) {
+        let chunks = (self.words.len() + CHUNK_WORDS - 1) / CHUNK_WORDS;
+        scope(|s| {
+            let f = &f;
+            for chunk in 0..chunks {
+                s.spawn(move |_| {
+                    for word in chunk * CHUNK_WORDS..min((chunk + 1) * CHUNK_WORDS, self.words.len()) {
+                        let mut bits = self.words[word].load(Ordering::Relaxed);
+                        while bits != 0 {
+                            f(word * 64 + bits.trailing_zeros() as usize);
+                            bits &= bits - 1;
+                        }
+                    }
+                });
             }
+        });
+    }
+}
+
+pub fn prev_layer_white<C: DtmCell>(dp: &Dp<C>, s: State, layer: u16, next: &Frontier) -> () {
+    for prev in s.previous_states() {
+        let packed = dp.index_of(&prev);
+        if dp.set_if_not_calculated(packed, layer) {
+            next.insert(packed);
+        }
+    }
+}
+
+// The distance to mate if black takes a piece, or None if one of the captures draws.
+// Black takes the piece that delays the mate the longest.
+pub fn capture_value(captures: &Captures, s: &State) -> Option<u16> {
+    let mut longest = 0;
+    for pos in s.captures().iter() {
+        match captures.get(&s.after_capture(pos)) {
+            WIDE_DRAW => return None,
+            value => longest = longest.max(value + 1),
+        }
+    }
+    Some(longest)
+}
+
+// The shortest helpmate after black takes a piece, or None if none of the captures leads to one.
+pub fn helpmate_capture_value(captures: &Captures, s: &State) -> Option<u16> {
+    s.captures().iter()
+        .map(|pos| captures.get(&s.after_capture(pos)))
+        .filter(|value| *value != WIDE_DRAW)
+        .map(|value| value + 1)
+        .min()
+}
+
+// the value of a deferred position once the search reaches it
+fn deferred_value(captures: &Captures, mode: Mode, s: &State) -> Option<u16> {
+    if mode.is_helpmate() {
+        helpmate_capture_value(captures, s)
+    }
+    else {
+        capture_value(captures, s)
+    }
+}
+
+// A symmetric state is reached from a position and from its mirror image, which share their index,
+// but the position only has one move to it. In a helpmate every move is counted, as any of them calculates the position.
+pub fn is_counted(s: &State, prev: &State, mode: Mode) -> bool {
+    mode.is_helpmate() || !s.is_symmetric() || prev.black_king.y <= prev.black_king.x
+}
+
+// Counts a move of a black position to a processed position, returns true if the position is calculated with this layer.
+// A black position whose captures take longer than its other moves is deferred until the search reaches its value.
+// In a helpmate black plays the first move that is found, so any predecessor is calculated right away.
+pub fn reach_black<C: DtmCell>(dp: &Dp<C>, captures: &Captures, mode: Mode, prev: &State, packed: usize, layer: u16, deferred: &Mutex<Vec<(u16, State)>>) -> bool {
+    if mode.is_helpmate() {
+        return dp.set_if_not_calculated(packed, layer);
+    }
+    if dp.take_move(packed, prev.next_states_count()) != Some(0) {
+        return false;
+    }
+    match capture_value(captures, prev) {
+        None => dp.set(packed, WIDE_DRAW),
+        Some(value) if value > layer => deferred.lock().unwrap().push((value, prev.normalize())),
+        Some(_) => {
+            dp.set(packed, layer);
+            return true;
         }
+    }
+    false
+}
 
-        dp[prev_packed].store(layer, Ordering::Relaxed);
-        sender.send(Message::Calculate(prev.normalize())).unwrap();
+pub fn prev_layer_black<C: DtmCell>(dp: &Dp<C>, captures: &Captures, mode: Mode, s: State, layer: u16, next: &Frontier, deferred: &Mutex<Vec<(u16, State)>>) -> () {
+    for prev in s.previous_states() {
+        if !is_counted(&s, &prev, mode) {
+            continue;
+        }
+        let prev_packed = dp.index_of(&prev);
+        if reach_black(dp, captures, mode, &prev, prev_packed, layer, deferred) {
+            next.insert(prev_packed);
+        }
     }
 }
 
@@ -76,126 +412,313 @@ fn fill_vec<T, F>(size: usize, f: F) -> Vec<T> where F: Fn() -> T {
     res
 }
 
-fn generate_checkmates(dp: &Vec<AtomicU8>, sender: &Sender<Message>) -> usize {
+// in the stalemate mode these are the stalemates instead, the mates are never reached and stay draws
+fn generate_checkmates<C: DtmCell>(dp: &Dp<C>, material: Material, mode: Mode, frontier: &Frontier) -> usize {
+    // the black king squares that count as checkmate, with the target they belong to
+    let mates: Vec<(Position, Target)> = (0..mode.target_count())
+        .map(|index| mode.target(index))
+        .flat_map(|target| target.squares().into_iter().map(move |black_king| (black_king, target)))
+        .collect();
     let added = AtomicUsize::new(0);
     scope(|s| {
         let added = &added;
-        for white_king in 0..16 {
-            let white_king_pos = Position::from_u8_bottom_left(white_king);
+        let mates = &mates;
+        for white_king in 0..10 {
+            let white_king_pos = Position::from_u8_triangle(white_king);
             s.spawn(move |_| {
-                for target in 0..28 {
-                    let target_pos = Position::from_u8_rim(target);
-                    let black_king_pos = target_pos;
-                    let black_king = black_king_pos.to_u8();
+                for (black_king_pos, target_pos) in mates {
+                    let black_king_pos = *black_king_pos;
                     if white_king_pos.king_moves().contains(black_king_pos) || white_king_pos == black_king_pos {
                         continue;
                     }
-                    for knight3 in 0..64 {
-                        if knight3 == white_king_pos.to_u8() || knight3 == black_king {
+                    let free = !(1u64 << white_king_pos.to_u8()) & !(1u64 << black_king_pos.to_u8());
+                    for pieces in material.placements(free) {
+                        let state = State {
+                            white_king: white_king_pos,
+                            material,
+                            pieces,
+                            black_king: black_king_pos,
+                            target: *target_pos,
+                            white_to_move: false,
+                        };
+                        // with the white king on the diagonal, a position and its mirror image are both generated but share their index
+                        let terminal = if mode.is_stalemate() { state.is_stalemate() } else { state.is_mate() };
+                        let packed = dp.index_of(&state);
+                        if terminal && dp.set_if_not_calculated(packed, 0) {
+                            frontier.insert(packed);
+                            added.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let added = added.load(Ordering::SeqCst);
+    println!("{} {} positions found", added, if mode.is_stalemate() { "stalemate" } else { "checkmate" });
+    added
+}
+
+// Black positions without any moves except captures are never reached by the search, their value only depends on the captures.
+// In a helpmate every position with a capture is deferred, the search may still reach it earlier.
+pub fn waits_for_captures(state: &State, mode: Mode) -> bool {
+    state.captures().count() > 0 && (mode.is_helpmate() || state.next_states_count() == 0)
+}
+
+// A count of 0 marks the position as seen, it is deferred like the ones in reach_black.
+pub fn defer_captures<C: DtmCell>(dp: &Dp<C>, captures: &Captures, mode: Mode, state: &State, packed: usize, deferred: &Mutex<Vec<(u16, State)>>) {
+    let value = deferred_value(captures, mode, state);
+    if (mode.is_helpmate() && value.is_none()) || !dp.mark_waiting(packed) {
+        return;
+    }
+    match value {
+        None => dp.set(packed, WIDE_DRAW),
+        Some(value) => deferred.lock().unwrap().push((value, state.normalize())),
+    }
+}
+
+fn generate_captures_only<C: DtmCell>(dp: &Dp<C>, captures: &Captures, material: Material, mode: Mode, deferred: &Mutex<Vec<(u16, State)>>) {
+    scope(|s| {
+        for white_king in 0..10 {
+            let white_king = Position::from_u8_triangle(white_king);
+            s.spawn(move |_| {
+                for black_king in (0..64).map(Position::from_u8) {
+                    if white_king.king_moves().contains(black_king) || white_king == black_king {
+                        continue;
+                    }
+                    let free = !(1u64 << white_king.to_u8()) & !(1u64 << black_king.to_u8());
+                    for pieces in material.placements(free) {
+                        let state = State { white_king, material, pieces, black_king, target: mode.target(0), white_to_move: false };
+                        if !waits_for_captures(&state, mode) {
                             continue;
                         }
-                        for knight2 in 0..knight3 {
-                            if knight2 == white_king_pos.to_u8() || knight2 == black_king {
-                                continue;
-                            }
-                            for knight1 in 0..knight2 {
-                                if knight1 == white_king_pos.to_u8() || knight1 == black_king {
-                                    continue;
-                                }
-                                let state = State {
-                                    white_king: white_king_pos,
-                                    black_king: black_king_pos,
-                                    knights: [Position::from_u8(knight1), Position::from_u8(knight2), Position::from_u8(knight3)],
-                                    target_field: target_pos,
-                                    white_to_move: false,
-                                };
-                                if state.pack() == 76359264 {
-                                    println!("Meme: {}", state.is_mate());
-                                }
-                                if state.is_mate() {
-                                    let packed = state.pack();
-                                    dp[packed as usize].store(0, Ordering::Relaxed);
-                                    sender.send(Message::Calculate(state.normalize())).unwrap();
-                                    added.fetch_add(1, Ordering::SeqCst);
-                                }
-                            }
+                        for index in 0..mode.target_count() {
+                            let state = State { target: mode.target(index), ..state };
+                            defer_captures(dp, captures, mode, &state, dp.index_of(&state), deferred);
                         }
                     }
                 }
             });
         }
     });
-    let added = added.load(Ordering::SeqCst);
-    println!("{} checkmate positions found", added);
+}
+
+// The positions found in the last layer are exactly the ones with that layer as their value.
+// Deferred positions are seen but not calculated yet, their captures are looked up again.
+fn load_frontier<C: DtmCell>(dp: &Dp<C>, captures: &Captures, mode: Mode, last_layer: u16, frontier: &Frontier, deferred: &Mutex<Vec<(u16, State)>>) -> usize {
+    let mut added = 0;
+    for packed in 0..dp.len() {
+        if dp.get(packed) == last_layer {
+            frontier.insert(packed);
+            added += 1;
+        }
+        else if dp.remaining_moves(packed) == Some(0) {
+            let state = dp.state_at(packed);
+            // an extended helpmate marks every position with a capture, see rebuild_counters
+            if let Some(value) = deferred_value(captures, mode, &state) {
+                deferred.lock().unwrap().push((value, state));
+            }
+        }
+    }
+    println!("{} positions of layer {} restored from checkpoint", added, last_layer);
     added
 }
 
-pub fn retrograde_search(pool: ThreadPool) -> Tablebase {
-    println!("Generating tablebase...");
-    pool.install( || {
-        let dp = fill_vec(16 * 63 * 28 * 2 * 37820, || AtomicU8::new(NOT_CALCULATED));
-        let outdeg = fill_vec(16 * 63 * 28 * 2 * 37820, || AtomicI8::new(-1));
+struct Frontiers {
+    // the positions found in the last layer, and the ones found in the layer that is calculated
+    current: Frontier,
+    next: Frontier,
+    // black positions with their value, waiting for the search to reach it
+    deferred: Mutex<Vec<(u16, State)>>,
+}
+
+impl Frontiers {
+    fn has_deferred(&self) -> bool {
+        !self.deferred.lock().unwrap().is_empty()
+    }
+
+    // no positions are left to be found
+    fn is_finished(&self, progress: &Progress) -> bool {
+        progress.added == 0 && !self.has_deferred()
+    }
+}
+
+struct Progress {
+    layer: u16,
+    white_to_play: bool,
+    // positions found in the last layer, these are the current frontier
+    added: usize,
+    processed: usize,
+}
+
+// calculates layers until no new positions are found, the next layer would collide with the sentinels or exceed the max depth
+fn search_layers<C: DtmCell>(dp: &Dp<C>, captures: &Captures, material: Material, mode: Mode, frontiers: &mut Frontiers, progress: &mut Progress, options: &mut GenOptions) {
+    let max_depth = options.max_depth.unwrap_or(u16::MAX);
+    while !frontiers.is_finished(progress) && progress.layer <= C::MAX_VALUE && progress.layer <= max_depth {
+        let start = Instant::now();
+        let white_to_play = progress.white_to_play;
+        let layer = progress.layer;
 
-        let (sender, receiver) = channel::unbounded();
-        let (buffer_sender, buffer_receiver) = channel::unbounded();
+        let Frontiers { current, next, deferred } = &*frontiers;
+        current.sweep(|index| {
+            let s = dp.state_at(index);
+            if white_to_play {
+                prev_layer_black(dp, captures, mode, s, layer, next, deferred);
+            } else {
+                prev_layer_white(dp, s, layer, next);
+            }
+        });
 
-        let mut white_to_play = false;
-        let mut layer = 1;
+        // in a helpmate the search may have reached the position before its captures
+        let mut waiting = deferred.lock().unwrap();
+        let (due, later): (Vec<(u16, State)>, Vec<(u16, State)>) = waiting.drain(..).partition(|(value, _)| *value == layer);
+        *waiting = later;
+        for (_, s) in due {
+            let packed = dp.index_of(&s);
+            if dp.set_if_not_calculated(packed, layer) {
+                next.insert(packed);
+            }
+        }
+        drop(waiting);
 
-        let mut added = generate_checkmates(&dp, &sender);
-        let mut processed = added;
+        progress.processed += progress.added;
+        let added = next.count();
+        current.clear();
+        let Frontiers { current, next, .. } = frontiers;
+        swap(current, next);
 
-        while added != 0 {
+        println!("{} positions found in layer {}. Processed {}/{} = {}% positions", added, layer, progress.processed, dp.len(), progress.processed as f32 * 100.0 / dp.len() as f32);
+        // the positions found from black positions have white to move
+        if let Some(report) = options.report.as_deref_mut() {
+            report.add_layer(dp, layer, !white_to_play, added, start.elapsed());
+        }
+        progress.added = added;
+        progress.white_to_play = !white_to_play;
+        progress.layer += 1;
 
-            for _ in 0..16 {
-                sender.send(Message::End).unwrap();
+        if let Some(path) = options.checkpoint {
+            if added != 0 || frontiers.has_deferred() {
+                write_checkpoint(path, material, mode, dp, progress.layer, progress.white_to_play);
             }
+        }
+    }
+}
 
-            let receiver = receiver.clone();
-            let buffer_sender_clone = buffer_sender.clone();
-            let dp = &dp;
-            let outdeg = &outdeg;
-
-            scope(move |s| {
-                for _ in 0..16 {
-                    let receiver = receiver.clone();
-                    let buffer_sender = buffer_sender_clone.clone();
-                    s.spawn(move |_| {
-                        loop {
-                            match receiver.recv().unwrap() {
-                                Message::End => break,
-                                Message::Calculate(s) =>
-                                    if white_to_play {
-                                        prev_layer_black(dp, outdeg, s, layer, &buffer_sender);
-                                    } else {
-                                        prev_layer_white(dp, s, layer, &buffer_sender);
-                                    }
-                            }
+// Counts the moves of the black positions that are not calculated yet, which lead to positions that were already processed.
+// With these counters the dp array is the state of the search after the layer max_depth, see Checkpoint::from_table.
+pub fn rebuild_counters<C: DtmCell>(dp: &Dp<C>, mode: Mode, max_depth: u16) {
+    let chunks = (dp.len() + (1 << 20) - 1) >> 20;
+    scope(|s| {
+        for chunk in 0..chunks {
+            s.spawn(move |_| {
+                for packed in chunk << 20..min((chunk + 1) << 20, dp.len()) {
+                    if dp.get(packed) != WIDE_NOT_CALCULATED {
+                        continue;
+                    }
+                    let state = dp.state_at(packed);
+                    // indices of positions whose mirror image has the index are never used
+                    if state.white_to_move || dp.index_of(&state) != packed {
+                        continue;
+                    }
+                    // only the captures of a helpmate are deferred, the other moves calculate a position as soon as they are reached
+                    if mode.is_helpmate() {
+                        if state.captures().count() > 0 {
+                            dp.set_remaining_moves(packed, 0);
                         }
-                    })
+                        continue;
+                    }
+                    let symmetric = state.is_symmetric();
+                    // the positions of the last layer are processed when the search continues
+                    let processed = state.next_states().iter()
+                        .filter(|next| !symmetric || next.black_king.y <= next.black_king.x)
+                        .filter(|next| dp.get(dp.index_of(next)) < max_depth)
+                        .count() as u8;
+                    let count = state.next_states_count();
+                    if processed > 0 || (count == 0 && state.captures().count() > 0) {
+                        dp.set_remaining_moves(packed, count - processed);
+                    }
                 }
             });
+        }
+    });
+}
 
-            buffer_sender.send(Message::End).unwrap();
+// How a table is generated, the defaults generate the complete table in memory with one byte per state.
+pub struct GenOptions<'a> {
+    // the index scheme of a new generation, a resumed one keeps the scheme of its checkpoint
+    pub scheme: IndexScheme,
+    // a checkpoint is written to this file after every layer
+    pub checkpoint: Option<&'a Path>,
+    pub resume: Option<Checkpoint>,
+    // switches to two bytes per state when the layers reach the sentinels of one byte tables, otherwise the generation is aborted
+    pub wide: bool,
+    // the generation stops after this layer and the table is bounded
+    pub max_depth: Option<u16>,
+    // gets the layers calculated by the generation, a resumed one only has the ones after its checkpoint
+    pub report: Option<&'a mut Report>,
+}
 
-            processed += added;
-            added = 0;
-            loop {
-                match buffer_receiver.recv().unwrap() {
-                    Message::End => break,
-                    calc => {
-                        added += 1;
-                        sender.send(calc).unwrap();
-                    }
+impl Default for GenOptions<'_> {
+    fn default() -> Self {
+        GenOptions { scheme: IndexScheme::Dense, checkpoint: None, resume: None, wide: false, max_depth: None, report: None }
+    }
+}
+
+// The tablebases of the materials after a capture are generated first, see Captures.
+pub fn retrograde_search(pool: ThreadPool, material: Material, mode: Mode, mut options: GenOptions) -> Result<Tablebase, String> {
+    let bound = options.max_depth.unwrap_or(u16::MAX);
+    let captures = Captures::generate(pool.current_num_threads(), material, mode)?;
+    println!("Generating {} tablebase...", material);
+    let tb = pool.install( || {
+        let frontier = Frontier::new(mode.state_count(material));
+        let deferred = Mutex::new(vec![]);
+
+        let (dp, mut progress) = match options.resume.take() {
+            Some(Checkpoint { dp, layer, white_to_play, .. }) => {
+                let added = match &dp {
+                    DpCells::Narrow(dp) => load_frontier(dp, &captures, mode, layer - 1, &frontier, &deferred),
+                    DpCells::Wide(dp) => load_frontier(dp, &captures, mode, layer - 1, &frontier, &deferred),
+                };
+                (dp, Progress { layer, white_to_play, added, processed: 0 })
+            }
+            None => {
+                let start = Instant::now();
+                let dp = Dp::new(fill_vec(mode.state_count(material), || AtomicU8::new(NOT_CALCULATED)), material, mode, options.scheme);
+                let added = generate_checkmates(&dp, material, mode, &frontier);
+                generate_captures_only(&dp, &captures, material, mode, &deferred);
+                if let Some(report) = options.report.as_deref_mut() {
+                    report.add_layer(&dp, 0, false, added, start.elapsed());
                 }
+                (DpCells::Narrow(dp), Progress { layer: 1, white_to_play: false, added, processed: 0 })
             }
+        };
+        let mut frontiers = Frontiers { current: frontier, next: Frontier::new(mode.state_count(material)), deferred };
 
-            println!("{} positions found in layer {}. Processed {}/{} = {}% positions", added, layer, processed, dp.len(), processed as f32 * 100.0 / dp.len() as f32);
-            white_to_play = !white_to_play;
-            layer += 1;
-        }
-
+        let dp = match dp {
+            DpCells::Narrow(dp) => {
+                search_layers(&dp, &captures, material, mode, &mut frontiers, &mut progress, &mut options);
+                if frontiers.is_finished(&progress) {
+                    return Ok(Tablebase::new(TableData::Owned(dp.to_bytes()), 1, material, mode, dp.scheme(), 0));
+                }
+                if progress.layer > bound {
+                    return Ok(Tablebase::new(TableData::Owned(dp.to_bytes()), 1, material, mode, dp.scheme(), bound));
+                }
+                if !options.wide {
+                    return Err(format!("Layer {} collides with the sentinel values of one byte tables, generate with --wide to store two bytes per state (the last checkpoint can be resumed with --wide)!", progress.layer));
+                }
+                println!("Switching to two bytes per state for layer {}...", progress.layer);
+                dp.widen()
+            }
+            DpCells::Wide(dp) => dp,
+        };
 
-        Tablebase{dp: dp.iter().map(|i| i.load(Ordering::Relaxed)).collect()}
-    })
+        search_layers(&dp, &captures, material, mode, &mut frontiers, &mut progress, &mut options);
+        if frontiers.is_finished(&progress) {
+            return Ok(Tablebase::new(TableData::Owned(dp.to_bytes()), 2, material, mode, dp.scheme(), 0));
+        }
+        if progress.layer > bound {
+            return Ok(Tablebase::new(TableData::Owned(dp.to_bytes()), 2, material, mode, dp.scheme(), bound));
+        }
+        Err(format!("Layer {} collides with the sentinel values of two byte tables!", progress.layer))
+    })?;
+    Ok(tb.with_captures(captures))
 }
@@ -1,11 +1,16 @@
-use crate::encoding::{PackedState, SmallState};
+use crate::encoding::{DenseState, IndexScheme, Mode, PackedState, SmallState};
+use crate::target::Target;
+use crate::material::{Material, MAX_PIECES, PIECES};
 use std::cmp::Ordering;
 use shakmaty::fen::Fen;
 use shakmaty::Color;
 use shakmaty::Square;
-use std::cmp::min;
-use std::cmp::max;
+use std::fmt;
 
+// index of the first square of every row of the triangle
+const TRIANGLE_ROWS: [u8; 4] = [0, 4, 7, 9];
+// the white pieces in a FEN, in the order of PIECES
+const LETTERS: [&str; 4] = ["N", "B", "R", "Q"];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Position {
@@ -46,19 +51,43 @@ impl Position {
         }
     }
 
+    // the rim squares keep their numbers, the squares inside follow row by row
+    pub fn from_u8_target(i: u8) -> Self {
+        assert!(i < 64);
+        if i < 28 {
+            Position::from_u8_rim(i)
+        } else {
+            Position { x: (i - 28) % 6 + 1, y: (i - 28) / 6 + 1 }
+        }
+    }
+    pub fn to_u8_target(self) -> u8 {
+        if self.is_on_rim() {
+            self.to_u8_rim()
+        } else {
+            28 + (self.y - 1) * 6 + self.x - 1
+        }
+    }
+
     pub fn is_on_rim(self) -> bool {
         self.x == 0 || self.y == 0 || self.x == 7 || self.y == 7
     }
 
-    pub fn from_u8_bottom_left(i: u8) -> Self {
-        assert!(i < 16);
-        Position { x: i & 0b11, y: i >> 2 }
-    }
     pub fn to_u8_bottom_left(&self) -> u8 {
         assert!(self.x < 4 && self.y < 4);
         self.x + (self.y << 2)
     }
 
+    // the 10 squares a1-d1, b2-d2, c3-d3 and d4, numbered row by row
+    pub fn from_u8_triangle(i: u8) -> Self {
+        assert!(i < 10);
+        let y = TRIANGLE_ROWS.iter().rposition(|start| *start <= i).unwrap() as u8;
+        Position { x: i - TRIANGLE_ROWS[y as usize] + y, y }
+    }
+    pub fn to_u8_triangle(&self) -> u8 {
+        assert!(self.x < 4 && self.y <= self.x);
+        TRIANGLE_ROWS[self.y as usize] + self.x - self.y
+    }
+
     pub fn rotate_clockwise(self) -> Self {
         Position { x: self.y, y: 7 - self.x }
     }
@@ -68,6 +97,10 @@ impl Position {
     pub fn rotate_twice(self) -> Self {
         Position { x: 7 - self.x, y: 7 - self.y }
     }
+    // reflection along the a1-h8 diagonal
+    pub fn mirror_diagonal(self) -> Self {
+        Position { x: self.y, y: self.x }
+    }
 
     pub fn is_out_of_bounds(&self, dx: i16, dy: i16) -> bool {
         self.x as i16 + dx > 7 || self.x as i16 + dx < 0 || self.y as i16 + dy > 7 || self.y as i16 + dy < 0
@@ -105,6 +138,12 @@ impl Position {
     }
 }
 
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", (b'a' + self.x) as char, (b'1' + self.y) as char)
+    }
+}
+
 impl Ord for Position {
     fn cmp(&self, other: &Position) -> Ordering {
         self.to_u8().cmp(&(*other).to_u8())
@@ -120,54 +159,50 @@ impl PartialOrd for Position {
 #[derive(Debug, Clone, Copy)]
 pub struct State {
     pub white_king: Position,
-    pub knights: [Position; 3],
+    pub material: Material,
+    // the first material.count() squares are used, see State::pieces
+    pub pieces: [Position; MAX_PIECES],
     pub black_king: Position,
-    pub target_field: Position,
+    pub target: Target,
     pub white_to_move: bool,
 }
 
 impl State {
-    pub fn unpack(packed: PackedState) -> Self {
-        let SmallState {
-            white_king: white_king_packed,
-            knights: knights_packed,
-            black_king: black_king_packed,
-            target_field: target_field_packed,
-            white_to_move: white_to_move_packed,
-        } = SmallState::decode(packed);
-
-        let white_king = Position::from_u8_bottom_left(white_king_packed);
-        let black_king = black_king_packed + if white_king.to_u8() <= black_king_packed { 1 } else { 0 };
-        let mut knights = [0u8; 3];
-
-        let mut prev: Vec<u8> = vec![white_king.to_u8(), black_king];
-        for i in 0..3 {
-            knights[i] = knights_packed[i];
-            prev.sort_unstable();
-            for j in &prev {
-                if *j <= knights[i] {
-                    knights[i] += 1;
-                }
-            }
-            prev.push(knights[i]);
-        }
-
-        let black_king = Position::from_u8(black_king);
-        let knights = [Position::from_u8(knights[0]), Position::from_u8(knights[1]), Position::from_u8(knights[2])];
-        let target_field = Position::from_u8_rim(target_field_packed);
-        let white_to_move = white_to_move_packed == 1;
-
-        State {
+    pub fn unpack(packed: PackedState, mode: Mode, material: Material, scheme: IndexScheme) -> Self {
+        let DenseState {
             white_king,
-            knights,
             black_king,
+            pieces,
+            target,
             white_to_move,
-            target_field,
+            ..
+        } = DenseState::decode(packed, mode, material, scheme);
+
+        let mut positions = [Position::from_u8(0); MAX_PIECES];
+        for i in 0..material.count() {
+            positions[i] = Position::from_u8(pieces[i]);
         }
+        State {
+            white_king: Position::from_u8_triangle(white_king),
+            material,
+            pieces: positions,
+            black_king: Position::from_u8(black_king),
+            white_to_move: white_to_move == 1,
+            target: mode.target(target as usize),
+        }
+    }
+
+    // the squares of the white pieces besides the king, sorted by their kind like the material
+    pub fn pieces(&self) -> &[Position] {
+        &self.pieces[..self.material.count()]
     }
 
     pub fn apply_to_positions(&self, f: &Fn(Position) -> Position) -> Self {
-        State { white_king: f(self.white_king), knights: [f(self.knights[0]), f(self.knights[1]), f(self.knights[2])], black_king: f(self.black_king), target_field: f(self.target_field), ..*self }
+        let mut pieces = self.pieces;
+        for piece in pieces[..self.material.count()].iter_mut() {
+            *piece = f(*piece);
+        }
+        State { white_king: f(self.white_king), pieces, black_king: f(self.black_king), target: self.target.map(f), ..*self }
     }
 
     pub fn rotate_clockwise(&self) -> Self {
@@ -182,17 +217,33 @@ impl State {
         self.apply_to_positions(&Position::rotate_twice)
     }
 
-    pub fn sort_knights(&self) -> Self {
-        let min_knight = min(self.knights[0], min(self.knights[1], self.knights[2]));
-        let max_knight = max(self.knights[0], max(self.knights[1], self.knights[2]));
-        let middle_knight = Position::from_u8(self.knights[0].to_u8() ^ self.knights[1].to_u8() ^ self.knights[2].to_u8() ^ max_knight.to_u8() ^ min_knight.to_u8());
+    pub fn mirror_diagonal(&self) -> Self {
+        self.apply_to_positions(&Position::mirror_diagonal)
+    }
+
+    pub fn black_king_on_target(&self) -> bool {
+        self.target.contains(self.black_king)
+    }
+
+    // unchanged by the reflection along the a1-h8 diagonal, pieces of the same kind can also swap their squares
+    pub fn is_symmetric(&self) -> bool {
+        let sorted = self.sort_pieces();
+        let mirrored = self.mirror_diagonal().sort_pieces();
+        self.target.is_symmetric() && (mirrored.white_king, mirrored.black_king, mirrored.pieces()) == (sorted.white_king, sorted.black_king, sorted.pieces())
+    }
 
-        assert!(min_knight < middle_knight && middle_knight < max_knight);
-        State { knights: [min_knight, middle_knight, max_knight], ..*self }
+    pub fn sort_pieces(&self) -> Self {
+        let mut pieces = self.pieces;
+        for (_, range) in self.material.groups() {
+            pieces[range.clone()].sort();
+            assert!(pieces[range].windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        State { pieces, ..*self }
     }
 
+    // rotates the white king into the bottom left quadrant and mirrors it into the triangle below the a1-h8 diagonal
     pub fn normalize(&self) -> State {
-        (if self.white_king.x >= 4 && self.white_king.y < 4 {
+        let rotated = (if self.white_king.x >= 4 && self.white_king.y < 4 {
             // lower right
             self.rotate_clockwise()
         } else if self.white_king.x < 4 && self.white_king.y >= 4 {
@@ -204,17 +255,67 @@ impl State {
         } else {
             // lower left
             *self
-        }).sort_knights()
+        }).sort_pieces();
+        if rotated.white_king.y > rotated.white_king.x {
+            rotated.mirror_diagonal().sort_pieces()
+        }
+        else if rotated.white_king.y == rotated.white_king.x {
+            // the mirrored state has the white king on the same square, so the other pieces decide
+            let mirrored = rotated.mirror_diagonal().sort_pieces();
+            if (mirrored.black_king, mirrored.pieces(), mirrored.target) < (rotated.black_king, rotated.pieces(), rotated.target) {
+                mirrored
+            }
+            else {
+                rotated
+            }
+        }
+        else {
+            rotated
+        }
+    }
+
+    fn pack_normalized(&self, (target, targets): (u8, u8), scheme: IndexScheme) -> PackedState {
+        // the slider checks of white have indices too, but they belong to no position and would collide with other ones
+        assert!(self.is_legal(), "{} has no index", self.to_lichess());
+        let mut pieces = [0u8; MAX_PIECES];
+        for (i, piece) in self.pieces().iter().enumerate() {
+            pieces[i] = piece.to_u8();
+        }
+        DenseState {
+            white_king: self.white_king.to_u8_triangle(),
+            black_king: self.black_king.to_u8(),
+            material: self.material,
+            pieces,
+            target,
+            targets,
+            white_to_move: self.white_to_move as u8,
+            scheme,
+        }
+            .encode()
+    }
+
+    pub fn pack(&self) -> PackedState {
+        let s = self.normalize();
+        s.pack_normalized(s.target.slice(), IndexScheme::Dense)
     }
 
-    fn pack_normalized(&self) -> PackedState {
+    // the index in a tablebase of the given mode and scheme, which only differs from pack if not every target field has a slice
+    pub fn pack_in(&self, mode: Mode, scheme: IndexScheme) -> PackedState {
+        let s = self.normalize();
+        s.pack_normalized(mode.slice(s.target), scheme)
+    }
+
+    // the index in the reflected scheme, which is only needed to migrate tablebases, these always have three knights and a target field on the rim
+    pub fn pack_reflected(&self) -> PackedState {
+        assert_eq!(self.material, Material::THREE_KNIGHTS);
         let State {
             white_king,
             black_king,
-            knights,
+            pieces: knights,
             white_to_move,
-            target_field,
-        } = self;
+            target,
+            ..
+        } = self.normalize();
         let black_king = black_king.to_u8() - if white_king < black_king { 1 } else { 0 };
 
         let mut knights_packed = [knights[0].to_u8(), knights[1].to_u8(), knights[2].to_u8()];
@@ -229,30 +330,29 @@ impl State {
             knights_packed[i] -= i as u8;
         }
 
-        let white_to_move = if self.white_to_move { 1u8 } else { 0u8 };
+        let white_to_move = if white_to_move { 1u8 } else { 0u8 };
 
         SmallState {
-            white_king: white_king.to_u8_bottom_left(),
+            white_king: white_king.to_u8_triangle(),
             black_king,
             knights: knights_packed,
-            target_field: target_field.to_u8_rim(),
+            target_field: match target {
+                Target::Field(target) => target.to_u8_rim(),
+                _ => panic!("Only target fields have an index in the reflected scheme!"),
+            },
             white_to_move,
         }
             .encode()
     }
 
-    pub fn pack(&self) -> PackedState {
-        self.normalize().pack_normalized()
-    }
-
     pub fn to_fen(self) -> String {
         let s = self.normalize();
         let mut result = String::from("");
         let mut position = [""; 64];
         position[s.white_king.to_u8() as usize] = "K";
         position[s.black_king.to_u8() as usize] = "k";
-        for knight in &s.knights {
-            position[knight.to_u8() as usize] = "N";
+        for (i, piece) in s.pieces().iter().enumerate() {
+            position[piece.to_u8() as usize] = LETTERS[s.material.kind(i) as usize];
         }
         let mut counter = 0;
         for i in 0..8 {
@@ -276,7 +376,7 @@ impl State {
         result + " " + if s.white_to_move { "w" } else { "b" } + " - - 0 1"
     }
 
-    pub fn from_fen(s: &String, target: Position) -> Result<Self, String> {
+    pub fn from_fen(s: &String, target: Target) -> Result<Self, String> {
         let fen = s.parse::<Fen>().unwrap();
 
         let black_king_opt = fen.board.king_of(Color::Black);
@@ -288,10 +388,10 @@ impl State {
         else if let None = white_king_opt {
             Err(String::from("No white king found!"))
         }
-        else if (fen.board.white() & fen.board.knights()).count() != 3 {
-            Err(String::from("Wrong amount of white knighs!"))
+        else if (fen.board.white() & fen.board.pawns()).any() {
+            Err(String::from("White can't have pawns!"))
         }
-        else if fen.board.white().count() != 4 {
+        else if fen.board.white().count() > MAX_PIECES + 1 {
             Err(String::from("Wrong amount of white pieces!"))
         }
         else if fen.board.black().count() != 1 {
@@ -300,20 +400,21 @@ impl State {
         else {
             let black_king = Position::from_square(black_king_opt.unwrap());
             let white_king = Position::from_square(white_king_opt.unwrap());
-            let mut i = 0;
-            let mut knights = [Position::from_u8(0), Position::from_u8(0), Position::from_u8(0)];
-            for square in fen.board.knights().into_iter() {
-                if fen.board.color_at(square).unwrap() == Color::White {
-                    knights[i] = Position::from_square(square);
-                    i += 1;
+            let mut kinds = vec![];
+            let mut pieces = [Position::from_u8(0); MAX_PIECES];
+            for (piece, squares) in PIECES.iter().zip(&[fen.board.knights(), fen.board.bishops(), fen.board.rooks(), fen.board.queens()]) {
+                for square in (fen.board.white() & *squares).into_iter() {
+                    pieces[kinds.len()] = Position::from_square(square);
+                    kinds.push(*piece);
                 }
-            };
+            }
             Ok(State {
                 white_king,
+                material: Material::from_pieces(&kinds),
+                pieces,
                 black_king,
-                knights,
                 white_to_move: fen.turn == Color::White,
-                target_field: target
+                target
             })
         }
     }
@@ -327,6 +428,6 @@ impl PartialEq for State {
     fn eq(&self, other: &State) -> bool {
         let normalized = self.normalize();
         let o_normalized = other.normalize();
-        (normalized.white_king, normalized.black_king, normalized.knights, normalized.target_field, normalized.white_to_move) == (o_normalized.white_king, o_normalized.black_king, o_normalized.knights, o_normalized.target_field, o_normalized.white_to_move)
+        (normalized.material, normalized.white_king, normalized.black_king, normalized.pieces(), normalized.target, normalized.white_to_move) == (o_normalized.material, o_normalized.white_king, o_normalized.black_king, o_normalized.pieces(), o_normalized.target, o_normalized.white_to_move)
     }
 }
@@ -3,71 +3,371 @@ use std::io::BufWriter;
 use std::io::Write;
 use std::io::BufReader;
 use std::io::Read;
-use crate::search::{retrograde_search, DRAW, NOT_CALCULATED};
+use crate::search::{retrograde_search, value_at, widen, GenOptions, WIDE_DRAW, WIDE_NOT_CALCULATED};
 use rayon::ThreadPoolBuilder;
 use crate::verification::verify;
 use indicatif::ProgressBar;
 use crate::state::{State, Position};
 use chess::{ChessMove, MoveGen, Board};
+use crate::header::{Header, checksum};
+use crate::encoding::{IndexScheme, Mode};
+use crate::compression::{BLOCK_SIZE, CompressedTable, compress_blocks, write_blocks};
+use crate::header::Layout;
+use crate::partition::Partitions;
+use crate::wdl::{to_bitmap, is_win};
+use crate::checkpoint::Checkpoint;
+use crate::external::external_search;
+use memmap::{Mmap, MmapOptions};
+use std::borrow::Cow;
+use std::path::Path;
+use crate::captures::Captures;
+use crate::material::Material;
+use crate::target::Target;
+
+pub enum TableData {
+    Owned(Vec<u8>),
+    // the mapping covers the whole file, the table starts after the header
+    Mapped(Mmap, usize),
+    Compressed(CompressedTable),
+}
+
+fn read_data<R: Read>(reader: &mut R, len: u64) -> Vec<u8> {
+    let mut dp = vec![0u8; len as usize];
+    let bar = ProgressBar::new(len);
+    for chunk in dp.chunks_mut(2000000) {
+        reader.read_exact(chunk).unwrap();
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish();
+    dp
+}
+
+fn map_file(file: &File) -> Result<(Mmap, Header), String> {
+    let mmap = unsafe { MmapOptions::new().map(file) }.map_err(|e| format!("Could not map the tablebase: {}", e))?;
+    let header = Header::read(&mut &mmap[..])?;
+    header.check_compatible()?;
+    if let Some(data_len) = header.data_len() {
+        if mmap.len() as u64 != header.len() as u64 + data_len {
+            return Err(String::from("Tablebase has the wrong size!"));
+        }
+    }
+    Ok((mmap, header))
+}
+
+pub fn write_table(file: File, dp: &[u8], header: Header) {
+    let mut writer = BufWriter::new(&file);
+    header.write(&mut writer).unwrap();
+    let bar = ProgressBar::new(dp.len() as u64);
+    for chunk in dp.chunks(2000000) {
+        writer.write_all(chunk).unwrap();
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish();
+}
+
+impl TableData {
+    pub fn len(&self) -> usize {
+        match self {
+            TableData::Owned(dp) => dp.len(),
+            TableData::Mapped(mmap, offset) => mmap.len() - offset,
+            TableData::Compressed(table) => table.len(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> u8 {
+        match self {
+            TableData::Owned(dp) => dp[index],
+            TableData::Mapped(mmap, offset) => mmap[offset + index],
+            TableData::Compressed(table) => table.get(index),
+        }
+    }
+
+    // the value of a state with the sentinels of wide tables
+    pub fn value(&self, index: usize, width: usize) -> u16 {
+        match self {
+            TableData::Owned(dp) => value_at(dp, width, index),
+            TableData::Mapped(mmap, offset) => value_at(&mmap[*offset..], width, index),
+            TableData::Compressed(table) => {
+                let block = table.block(index * width / BLOCK_SIZE);
+                value_at(&block, width, index % (BLOCK_SIZE / width))
+            }
+        }
+    }
+
+    // the whole table, compressed tables are decompressed for this
+    pub fn raw(&self) -> Cow<[u8]> {
+        match self {
+            TableData::Owned(dp) => Cow::Borrowed(dp),
+            TableData::Mapped(mmap, offset) => Cow::Borrowed(&mmap[*offset..]),
+            TableData::Compressed(table) => Cow::Owned(table.decompress()),
+        }
+    }
+
+    pub fn read(file: &File) -> Result<(Self, Header), String> {
+        let mut reader = BufReader::new(file);
+        let header = Header::read(&mut reader)?;
+        header.check_compatible()?;
+        let dp = match header.data_len() {
+            Some(data_len) => {
+                if file.metadata().unwrap().len() != header.len() as u64 + data_len {
+                    return Err(String::from("Tablebase has the wrong size!"));
+                }
+                read_data(&mut reader, data_len)
+            }
+            None => {
+                let (mmap, header) = map_file(file)?;
+                CompressedTable::open(mmap, header.len(), (header.entries * header.value_width as u64) as usize, BLOCK_SIZE)?.decompress()
+            }
+        };
+        header.verify_checksum(&dp)?;
+        Ok((TableData::Owned(dp), header))
+    }
+
+    // The checksum is not verified here, as that would read the whole file and defeat the purpose of mapping it
+    pub fn map(file: &File) -> Result<(Self, Header), String> {
+        let (mmap, header) = map_file(file)?;
+        let offset = header.len();
+        let dp = match header.layout {
+            Layout::Raw | Layout::Bitmap => TableData::Mapped(mmap, offset),
+            Layout::Compressed => TableData::Compressed(CompressedTable::open(mmap, offset, (header.entries * header.value_width as u64) as usize, BLOCK_SIZE)?),
+        };
+        Ok((dp, header))
+    }
+}
+
+enum Storage {
+    Single(TableData),
+    Partitioned(Partitions),
+    // only knows whether white can force mate, not the distance
+    Wdl(TableData),
+}
 
 pub struct Tablebase {
-    pub dp: Vec<u8>
+    storage: Storage,
+    // bytes per state
+    width: usize,
+    material: Material,
+    mode: Mode,
+    // how the positions are ordered, see IndexScheme
+    scheme: IndexScheme,
+    // see Header::max_depth, positions that are not calculated in a bounded table are unknown
+    max_depth: u16,
+    // NOT_CALCULATED is reported as DRAW, unless the table is bounded
+    normalized: bool,
+    // only needed after black took a piece, a generated table keeps the ones of its search, an opened one gets them with load_captures
+    captures: Option<Captures>,
 }
 
 pub enum Value {
-    MateIn(u8),
-    Draw
+    MateIn(u16),
+    // white can force mate, but the table only has win/draw information
+    Win,
+    Draw,
+    // the table was generated with --max-depth and the position wasn't decided up to that depth
+    Unknown,
 }
 
 pub struct Evaluation {
     pub best_moves: Vec<ChessMove>,
-    pub value: Value
+    pub value: Value,
+    // With the fifty-move rule, Some(true) if the mate surely comes before the game is drawn. Some(false) only means that
+    // this isn't guaranteed, a capture of black restarts the clock and may still bring the mate in time. None without a clock or a mate.
+    pub in_time: Option<bool>,
+}
+
+// the halfmove clock of a FEN, 0 if the FEN doesn't have one
+pub fn halfmove_clock(fen: &str) -> Result<u16, String> {
+    match fen.split_whitespace().nth(4) {
+        Some(clock) => match clock.parse() {
+            // the game is drawn once the clock reaches 100
+            Ok(clock) if clock <= 100 => Ok(clock),
+            _ => Err(format!("{} is not a valid halfmove clock (has to be between 0 and 100)!", clock)),
+        },
+        None => Ok(0),
+    }
 }
 
 impl Tablebase {
+    pub fn new(dp: TableData, width: usize, material: Material, mode: Mode, scheme: IndexScheme, max_depth: u16) -> Self {
+        Tablebase { storage: Storage::Single(dp), width, material, mode, scheme, max_depth, normalized: false, captures: None }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn material(&self) -> Material {
+        self.material
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // 0 if the table is complete
+    pub fn max_depth(&self) -> u16 {
+        self.max_depth
+    }
+
+    pub fn scheme(&self) -> IndexScheme {
+        self.scheme
+    }
+
+    // the index of a position in this table
+    pub fn index_of(&self, state: &State) -> usize {
+        state.pack_in(self.mode, self.scheme) as usize
+    }
+
+    fn header(&self, width: u8, checksum: u32) -> Header {
+        Header { max_depth: self.max_depth, index_scheme: self.scheme, ..Header::new(self.material, self.mode, width, checksum) }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Single(dp) => dp.len() / self.width,
+            Storage::Partitioned(_) | Storage::Wdl(_) => self.mode.state_count(self.material),
+        }
+    }
+
+    // the whole table, all slices of a partitioned tablebase have to be available for this
+    pub fn raw(&self) -> Cow<[u8]> {
+        match &self.storage {
+            Storage::Single(dp) => dp.raw(),
+            Storage::Partitioned(partitions) => Cow::Owned(partitions.raw().unwrap_or_else(|e| panic!("{}", e))),
+            Storage::Wdl(_) => panic!("A win/draw bitmap contains no distances to mate!"),
+        }
+    }
+
     pub fn write_to_disk(&self, file: File) {
+        println!("Writing tablebase to disk...");
+        let dp = self.raw();
+        write_table(file, &dp, self.header(self.width as u8, checksum(&dp)));
+    }
+
+    // returns the compressed size of every block
+    pub fn write_compressed_to_disk(&self, file: File) -> Vec<usize> {
+        let dp = self.raw();
+        println!("Compressing tablebase...");
+        let blocks = compress_blocks(&dp);
         println!("Writing tablebase to disk...");
         let mut writer = BufWriter::new(&file);
-        let mut i = 0;
-        let bar = ProgressBar::new(self.dp.len()as u64);
-        for r in &self.dp {
-            writer.write(&[*r]).unwrap();
-            i += 1;
-            if i == 2000000 {
-                bar.inc(2000000);
-                i = 0;
-            }
+        Header { layout: Layout::Compressed, ..self.header(self.width as u8, checksum(&dp)) }.write(&mut writer).unwrap();
+        write_blocks(&mut writer, &blocks).unwrap();
+        blocks.iter().map(|block| block.len()).collect()
+    }
+
+    pub fn write_wdl_to_disk(&self, file: File) {
+        let dp = self.raw();
+        println!("Building win/draw bitmap...");
+        let bitmap = to_bitmap(&dp, self.width);
+        println!("Writing win/draw bitmap to disk...");
+        write_table(file, &bitmap, Header { layout: Layout::Bitmap, ..self.header(1, checksum(&bitmap)) });
+    }
+
+    pub fn write_partitioned_to_disk(&self, directory: &Path) {
+        // the slices are written and read as Mode::Target, helpmates and stalemates would be taken for checkmates
+        assert!(match self.mode { Mode::Target | Mode::Fields(_) => true, _ => false }, "Only checkmate tablebases with target fields can be partitioned!");
+        println!("Writing partitioned tablebase to disk...");
+        Partitions::write(directory, &self.raw(), self.width, self.material, self.mode, self.scheme, self.max_depth);
+    }
+
+    fn from_complete(dp: TableData, header: Header) -> Result<Self, String> {
+        if !header.is_complete() {
+            Err(String::from("Tablebase file only contains a slice, open its directory instead!"))
+        }
+        else if header.layout == Layout::Bitmap {
+            Ok(Tablebase { storage: Storage::Wdl(dp), width: 1, material: header.material, mode: header.mode, scheme: header.index_scheme, max_depth: header.max_depth, normalized: false, captures: None })
+        }
+        else {
+            Ok(Tablebase::new(dp, header.value_width as usize, header.material, header.mode, header.index_scheme, header.max_depth))
         }
-        bar.finish();
     }
 
-    pub fn read_from_disk(file: File) -> Result<Self, &'static str> {
+    pub fn read_from_disk(file: File) -> Result<Self, String> {
         println!("Reading tablebase from disk...");
-        if file.metadata().unwrap().len() != 16 * 63 * 28 * 2 * 37820 {
-            Err("Tablebase has the wrong size!")
+        let (dp, header) = TableData::read(&file)?;
+        Tablebase::from_complete(dp, header)
+    }
+
+    pub fn map_from_disk(file: File) -> Result<Self, String> {
+        let (dp, header) = TableData::map(&file)?;
+        Tablebase::from_complete(dp, header)
+    }
+
+    // without targets every slice is loaded on first use, otherwise only the slices needed for the targets are loaded right away
+    pub fn open_partitioned(directory: &Path, in_memory: bool, targets: Option<&[Position]>) -> Result<Self, String> {
+        let partitions = Partitions::open(directory, in_memory, targets)?;
+        let (material, width, scheme, max_depth) = (partitions.material(), partitions.width(), partitions.scheme(), partitions.max_depth());
+        // the slices of a table with only some target fields are the same as the ones of Mode::Target, just fewer
+        Ok(Tablebase { storage: Storage::Partitioned(partitions), width, material, mode: Mode::Target, scheme, max_depth, normalized: false, captures: None })
+    }
+
+    pub fn has_target(&self, target: Position) -> bool {
+        match &self.storage {
+            Storage::Single(_) | Storage::Wdl(_) => true,
+            Storage::Partitioned(partitions) => partitions.has_target(target),
         }
-        else {
-            let reader = BufReader::new(&file);
-            let mut dp = Vec::new();
-            let bar = ProgressBar::new(file.metadata().unwrap().len());
-            let mut i = 0;
-            for r in reader.bytes() {
-                dp.push(r.unwrap());
-                i += 1;
-                if i == 2000000 {
-                    bar.inc(2000000);
-                    i = 0;
-                }
+    }
+
+    // generates the slices of a missing target field and adds them to the directory of a partitioned tablebase
+    pub fn generate_target(&self, target: Position, threads: usize) -> Result<(), String> {
+        let partitions = match &self.storage {
+            Storage::Partitioned(partitions) => partitions,
+            _ => return Ok(()),
+        };
+        partitions.add_target(target, |mode, width, max_depth| {
+            println!("Generating target field {}...", target);
+            let options = GenOptions { scheme: self.scheme, wide: width == 2, max_depth: if max_depth == 0 { None } else { Some(max_depth) }, ..GenOptions::default() };
+            let tb = Tablebase::generate(threads, self.material, mode, options)?;
+            if tb.width > width {
+                return Err(format!("The mates on {} are too long for the one byte slices of the tablebase!", target));
             }
-            bar.finish();
-            Ok(Tablebase { dp })
+            let dp = tb.raw();
+            if tb.width == width {
+                return Ok(dp.into_owned());
+            }
+            Ok(dp.iter().flat_map(|value| widen(*value).to_le_bytes().to_vec()).collect())
+        })
+    }
+
+    pub fn has_distances(&self) -> bool {
+        match &self.storage {
+            Storage::Wdl(_) => false,
+            _ => true,
         }
     }
 
     pub fn normalize(&mut self) {
-        for i in 0..self.dp.len() {
-            if self.dp[i] == NOT_CALCULATED {
-                self.dp[i] = DRAW;
+        self.normalized = true;
+    }
+
+    // The distance to mate in halfmoves, with the sentinels of wide tables.
+    // The slices of a partitioned tablebase are loaded on first use, which fails if their file is missing or broken.
+    pub fn probe(&self, index: usize) -> Result<u16, String> {
+        let value = match &self.storage {
+            Storage::Single(dp) => dp.value(index, self.width),
+            Storage::Partitioned(partitions) => partitions.get(index)?,
+            Storage::Wdl(_) => return Err(String::from("A win/draw bitmap contains no distances to mate!")),
+        };
+        if self.normalized && value == WIDE_NOT_CALCULATED && self.max_depth == 0 {
+            Ok(WIDE_DRAW)
+        }
+        else {
+            Ok(value)
+        }
+    }
+
+    // like probe, for tables that can't fail like the ones of a generation
+    pub fn get(&self, index: usize) -> u16 {
+        self.probe(index).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn is_win(&self, index: usize) -> Result<bool, String> {
+        match &self.storage {
+            Storage::Wdl(bitmap) => Ok(is_win(bitmap, index)),
+            _ => {
+                let value = self.probe(index)?;
+                Ok(value != WIDE_DRAW && value != WIDE_NOT_CALCULATED)
             }
         }
     }
@@ -76,46 +376,298 @@ impl Tablebase {
         let mut mate = 0;
         let mut draw = 0;
         let mut not_calculated = 0;
-        for s in &self.dp {
-            match *s {
-                DRAW => draw += 1,
-                NOT_CALCULATED => not_calculated += 1,
+        let dp = self.raw();
+        for i in 0..self.len() {
+            match value_at(&dp, self.width, i) {
+                WIDE_NOT_CALCULATED if self.normalized => draw += 1,
+                WIDE_DRAW => draw += 1,
+                WIDE_NOT_CALCULATED => not_calculated += 1,
                 _ => mate += 1
             }
         }
-        let percent = 100.0 / self.dp.len() as f32;
-        println!("{} ({}%) mate, {} ({}%) draw, {} ({}%) not calculated from {} total", mate, mate as f32 * percent, draw, draw as f32  * percent, not_calculated, not_calculated as f32  * percent, self.dp.len())
+        let percent = 100.0 / self.len() as f32;
+        println!("{} ({}%) mate, {} ({}%) draw, {} ({}%) not calculated from {} total", mate, mate as f32 * percent, draw, draw as f32  * percent, not_calculated, not_calculated as f32  * percent, self.len())
     }
 
-    pub fn generate(threads: usize) -> Self {
-        retrograde_search(ThreadPoolBuilder::new().num_threads(threads).build().unwrap())
+    pub fn generate(threads: usize, material: Material, mode: Mode, options: GenOptions) -> Result<Self, String> {
+        retrograde_search(ThreadPoolBuilder::new().num_threads(threads).build().unwrap(), material, mode, options)
     }
 
-    pub fn verify(&self, threads: usize) -> bool {
-        verify(&self.dp, ThreadPoolBuilder::new().num_threads(threads).build().unwrap())
+    // generates the table into the file at path with about memory_limit bytes, see external_search
+    pub fn generate_external(threads: usize, material: Material, mode: Mode, memory_limit: usize, path: &Path, options: GenOptions) -> Result<Self, String> {
+        external_search(ThreadPoolBuilder::new().num_threads(threads).build().unwrap(), material, mode, memory_limit, path, options)
     }
 
-    pub fn eval(&self, board: Board, target: Position) -> Evaluation {
-        let s = State::from_board(board, target);
-        if !s.target_field.is_on_rim() {
-            Evaluation {best_moves: vec![], value: Value::Draw}
+    // resuming the checkpoint of a bounded table continues its generation, up to max_depth or until it is complete
+    pub fn checkpoint(&self, threads: usize, max_depth: Option<u16>) -> Result<Checkpoint, String> {
+        if self.max_depth == 0 {
+            return Err(String::from("The tablebase is already complete!"));
+        }
+        if max_depth.map_or(false, |max_depth| max_depth <= self.max_depth) {
+            return Err(format!("The tablebase is already generated up to a depth of {}!", self.max_depth));
+        }
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        Ok(pool.install(|| Checkpoint::from_table(&self.raw(), self.width, self.material, self.mode, self.scheme, self.max_depth)))
+    }
+
+    // the tables after a capture have to be loaded for this
+    pub fn verify(&self, threads: usize) -> Result<bool, String> {
+        Ok(verify(&self.raw(), self.width, self.material, self.mode, self.scheme, self.captures()?, ThreadPoolBuilder::new().num_threads(threads).build().unwrap()))
+    }
+
+    pub fn with_captures(self, captures: Captures) -> Self {
+        Tablebase { captures: Some(captures), ..self }
+    }
+
+    // Maps the tables after a capture that are stored next to the tablebase at path, which probing needs once black can take a piece.
+    // A win/draw bitmap gets their bitmaps. Missing tables are an error, see Captures::open.
+    pub fn load_captures(&mut self, path: &Path) -> Result<(), String> {
+        if self.captures.is_none() {
+            self.captures = Some(Captures::open(path, &self.material.capture_tables(), self.mode.capture_mode(), self.has_distances())?);
+        }
+        Ok(())
+    }
+
+    // writes the tables after a capture of a generated tablebase next to it, see capture_path
+    pub fn write_captures_to_disk(&self, path: &Path) -> Result<(), String> {
+        match &self.captures {
+            Some(captures) => captures.write(path),
+            None => Ok(()),
+        }
+    }
+
+    // writes the win/draw bitmaps of the tables after a capture next to the bitmap at path, see capture_path
+    pub fn write_wdl_captures_to_disk(&self, path: &Path) -> Result<(), String> {
+        self.captures()?.write_wdl(path)
+    }
+
+    fn captures(&self) -> Result<&Captures, String> {
+        self.captures.as_ref().ok_or_else(|| format!("The tablebases after a capture of {} are not loaded!", self.material))
+    }
+
+    // the value of a position with the material of the tablebase or less, black may have taken more than one piece
+    pub fn value_of(&self, board: &Board, target: Target) -> Result<u16, String> {
+        let state = State::from_board(*board, target)?;
+        if state.material != self.material {
+            self.captures()?.value_of(board, target)
         }
         else {
-            let dp_s = self.dp[s.pack() as usize];
-            println!("{}", dp_s);
-            let best_moves = if s.white_to_move {
-                MoveGen::new_legal(&board)
-                    .filter(|m| { let next = self.dp[State::from_board(board.make_move_new(*m), s.target_field).pack() as usize]; println!("{:?}: {}", m, next); dp_s == DRAW || next == dp_s - 1 })
-                    .collect()
+            self.probe(self.index_of(&state))
+        }
+    }
+
+    // the value after a move, which may capture a piece
+    fn value_after(&self, board: &Board, m: ChessMove, target: Target) -> Result<u16, String> {
+        self.value_of(&board.make_move_new(m), target)
+    }
+
+    // The shortest lines of a helpmate, where every move of both sides keeps the mate as close as possible.
+    // There are often lots of them, so the search stops after the given number of lines.
+    pub fn solutions(&self, board: Board, target: Option<Position>, limit: usize) -> Result<Vec<Vec<ChessMove>>, String> {
+        let target = self.mode.target_of(target);
+        let mut solutions = vec![];
+        self.find_solutions(board, target, self.value_of(&board, target)?, &mut vec![], &mut solutions, limit)?;
+        Ok(solutions)
+    }
+
+    fn find_solutions(&self, board: Board, target: Target, value: u16, line: &mut Vec<ChessMove>, solutions: &mut Vec<Vec<ChessMove>>, limit: usize) -> Result<(), String> {
+        if solutions.len() >= limit || value >= WIDE_NOT_CALCULATED {
+            return Ok(());
+        }
+        if value == 0 {
+            solutions.push(line.clone());
+            return Ok(());
+        }
+        for m in MoveGen::new_legal(&board) {
+            let next = board.make_move_new(m);
+            if self.value_of(&next, target)? == value - 1 {
+                line.push(m);
+                self.find_solutions(next, target, value - 1, line, solutions, limit)?;
+                line.pop();
+            }
+        }
+        Ok(())
+    }
+
+    // the position in the table, an error if the tablebase doesn't have it
+    pub fn state_of(&self, board: Board, target: Option<Position>) -> Result<State, String> {
+        let s = State::from_board(board, self.mode.target_of(target))?;
+        if s.material != self.material {
+            return Err(format!("The position has the material {}, but the tablebase is for {}!", s.material, self.material));
+        }
+        Ok(s)
+    }
+
+    // Target is only used by tablebases with target fields, and has to be given for them.
+    // With the halfmove clock the mate also has to come before the fifty-move rule draws the game.
+    pub fn eval(&self, board: Board, target: Option<Position>, clock: Option<u16>) -> Result<Evaluation, String> {
+        let s = self.state_of(board, target)?;
+        if !self.has_distances() {
+            return self.eval_wdl(board, s);
+        }
+        let dp_s = self.probe(self.index_of(&s))?;
+        let moves: Vec<(ChessMove, u16, bool)> = MoveGen::new_legal(&board)
+            .map(|m| {
+                let next = self.value_after(&board, m, s.target)?;
+                Ok((m, next, board.piece_on(m.get_dest()).is_some()))
+            })
+            .collect::<Result<_, String>>()?;
+        let is_mate = |value: u16| value < WIDE_NOT_CALCULATED;
+        // A capture restarts the clock, which only helps white. So the mate surely comes in time if it fits without that.
+        let in_time = clock.filter(|_| is_mate(dp_s)).map(|clock| clock as u32 + dp_s as u32 <= 100);
+        // with an unknown value white keeps the positions that may still be won and black the ones that may still be drawn,
+        // in a helpmate black plays like white
+        let mates = s.white_to_move || self.mode.is_helpmate();
+        let optimal = if mates {
+            moves.iter()
+                .filter(|(_, next, _)| dp_s == WIDE_DRAW || (dp_s == WIDE_NOT_CALCULATED && *next == WIDE_NOT_CALCULATED) || *next == dp_s - 1)
+                .map(|(m, _, _)| *m)
+                .collect()
+        }
+        else {
+            moves.iter()
+                .filter(|(_, next, _)| (dp_s == WIDE_DRAW && *next == WIDE_DRAW) || (dp_s == WIDE_NOT_CALCULATED && *next >= WIDE_NOT_CALCULATED) || (is_mate(dp_s) && dp_s - 1 == *next))
+                .map(|(m, _, _)| *m)
+                .collect()
+        };
+        // With the clock the side that mates prefers the moves after which the mate surely comes in time, and the other side
+        // the moves after which it doesn't. Among them the mate is still kept as short or delayed as long as possible.
+        let preferred: Vec<(ChessMove, u16)> = match clock {
+            Some(clock) => moves.iter()
+                .filter(|(_, next, capture)| (is_mate(*next) && *next as u32 + if *capture { 0 } else { clock as u32 + 1 } <= 100) == mates)
+                .map(|(m, next, _)| (*m, *next))
+                .collect(),
+            None => vec![],
+        };
+        let best = if mates { preferred.iter().map(|(_, next)| *next).min() } else { preferred.iter().map(|(_, next)| *next).max() };
+        let best_moves = match best {
+            Some(best) => preferred.iter().filter(|(_, next)| *next == best).map(|(m, _)| *m).collect(),
+            None => optimal,
+        };
+        let value = match dp_s {
+            WIDE_DRAW => Value::Draw,
+            WIDE_NOT_CALCULATED => Value::Unknown,
+            _ => Value::MateIn(dp_s),
+        };
+        Ok(Evaluation {best_moves, value, in_time})
+    }
+
+    fn eval_wdl(&self, board: Board, s: State) -> Result<Evaluation, String> {
+        let win = self.is_win(self.index_of(&s))?;
+        let mut best_moves = vec![];
+        for m in MoveGen::new_legal(&board) {
+            let next = State::from_board(board.make_move_new(m), s.target)?;
+            let next = if next.material != self.material {
+                self.captures()?.is_win(&next)?
             }
             else {
-                println!("{}", dp_s);
-                MoveGen::new_legal(&board)
-                    .filter(|m| { let next = self.dp[State::from_board(board.make_move_new(*m), s.target_field).pack() as usize]; println!("{:?}: {}", m, next); (dp_s == DRAW && next == DRAW) || (dp_s != DRAW && dp_s - 1 == next) })
-                    .collect()
+                self.is_win(self.index_of(&next))?
+            };
+            // white keeps the win and black keeps the draw (or the win of a helpmate), all other moves are equally good
+            let keeps = if s.white_to_move || self.mode.is_helpmate() { !win || next } else { win || !next };
+            if keeps {
+                best_moves.push(m);
+            }
+        }
+        // a bounded bitmap doesn't know which of the other positions are drawn
+        let value = if win { Value::Win } else if self.max_depth != 0 { Value::Unknown } else { Value::Draw };
+        Ok(Evaluation {best_moves, value, in_time: None})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::PackedState;
+
+    // both tables start from the same checkmates, and black helping can only make the mates shorter
+    #[test]
+    fn helpmates_are_not_longer_than_mates() {
+        let material = Material::from_string("KRvK").unwrap();
+        let mates = Tablebase::generate(2, material, Mode::Target, GenOptions::default()).unwrap();
+        let helpmates = Tablebase::generate(2, material, Mode::Helpmate, GenOptions::default()).unwrap();
+        assert_eq!(mates.len(), helpmates.len());
+        for index in 0..mates.len() {
+            let (mate, helpmate) = (mates.get(index), helpmates.get(index));
+            assert_eq!(mate == 0, helpmate == 0, "{}", index);
+            if mates.is_win(index).unwrap() {
+                assert!(helpmates.is_win(index).unwrap() && helpmate <= mate, "{}: helpmate in {}, mate in {}", index, helpmate, mate);
+            }
+        }
+    }
+
+    // the longest mate of KRvK takes 16 moves, which are 31 halfmoves with white to move and 32 with black to move
+    #[test]
+    fn longest_mate_anywhere() {
+        let material = Material::from_string("KRvK").unwrap();
+        let tb = Tablebase::generate(2, material, Mode::Anywhere, GenOptions::default()).unwrap();
+        let longest = |white_to_move: bool| (0..tb.len())
+            .filter(|index| State::unpack(*index as PackedState, Mode::Anywhere, material, IndexScheme::Dense).white_to_move == white_to_move)
+            .filter(|index| tb.is_win(*index).unwrap())
+            .map(|index| tb.get(index))
+            .max();
+        assert_eq!(longest(true), Some(31));
+        assert_eq!(longest(false), Some(32));
+    }
+
+    // only the stalemates on the target field are won in 0, the mates and the other stalemates are draws
+    #[test]
+    fn stalemates_on_the_target_are_the_only_wins_in_zero() {
+        let material = Material::from_string("KRvK").unwrap();
+        let tb = Tablebase::generate(2, material, Mode::Stalemate, GenOptions::default()).unwrap();
+        for index in 0..tb.len() {
+            let state = State::unpack(index as PackedState, Mode::Stalemate, material, IndexScheme::Dense);
+            // the mirror images share the index of their normalized position
+            if state.white_to_move || !state.is_legal() || state.pack_in(Mode::Stalemate, IndexScheme::Dense) != index as PackedState {
+                continue;
+            }
+            let goal = state.is_stalemate() && state.black_king_on_target();
+            assert_eq!(tb.get(index) == 0, goal, "{}", state.to_lichess());
+            if state.is_mate() || state.is_stalemate() && !goal {
+                assert!(!tb.is_win(index).unwrap(), "{}", state.to_lichess());
+            }
+        }
+    }
+
+    // the bitmap and the ones of the tables after a capture give the same results as the distances
+    #[test]
+    fn bitmaps_agree_with_distances() {
+        let material = Material::from_string("KRRvK").unwrap();
+        let directory = std::env::temp_dir().join(format!("3n2k_wdl_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let (path, wdl) = (directory.join("krrk.tb"), directory.join("krrk.wdl"));
+        let tb = Tablebase::generate(2, material, Mode::Anywhere, GenOptions::default()).unwrap();
+        tb.write_to_disk(File::create(&path).unwrap());
+        tb.write_captures_to_disk(&path).unwrap();
+        tb.write_wdl_to_disk(File::create(&wdl).unwrap());
+        tb.write_wdl_captures_to_disk(&wdl).unwrap();
+        let mut distances = Tablebase::map_from_disk(File::open(&path).unwrap()).unwrap();
+        distances.normalize();
+        distances.load_captures(&path).unwrap();
+        let mut bitmap = Tablebase::map_from_disk(File::open(&wdl).unwrap()).unwrap();
+        bitmap.load_captures(&wdl).unwrap();
+        for index in (0..tb.len()).step_by(101) {
+            let state = State::unpack(index as PackedState, Mode::Anywhere, material, IndexScheme::Dense);
+            let board = match Board::from_fen(state.to_fen()).filter(|board| board.is_sane()) {
+                Some(board) if state.is_legal() => board,
+                _ => continue,
             };
-            let value = if dp_s == DRAW { Value::Draw } else { Value::MateIn(dp_s) };
-            Evaluation {best_moves, value}
+            let (exact, win) = (distances.eval(board, None, None).unwrap(), bitmap.eval(board, None, None).unwrap());
+            match (&exact.value, &win.value) {
+                (Value::MateIn(_), Value::Win) | (Value::Draw, Value::Draw) => {}
+                _ => panic!("{} has different values", state.to_lichess()),
+            }
+            // the bitmap keeps the win or the draw with every move that does, the shortest mates are among them
+            assert!(exact.best_moves.iter().all(|m| win.best_moves.contains(m)), "{}", state.to_lichess());
         }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn halfmove_clock_is_bounded() {
+        assert_eq!(halfmove_clock("8/8/8/8/8/8/8/K1k5 w - - 37 80"), Ok(37));
+        assert_eq!(halfmove_clock("8/8/8/8/8/8/8/K1k5 w - -"), Ok(0));
+        assert!(halfmove_clock("8/8/8/8/8/8/8/K1k5 w - - 101 80").is_err());
+        assert!(halfmove_clock("8/8/8/8/8/8/8/K1k5 w - - 65535 80").is_err());
     }
 }
\ No newline at end of file
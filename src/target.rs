@@ -0,0 +1,95 @@
+use crate::state::Position;
+use crate::encoding::TARGET_COUNT;
+
+// the squares the black king has to be mated on for white to win
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Target {
+    // a single square, the tablebase has a slice for each of them
+    Field(Position),
+    // a set of squares, the tablebase has a slice for each of its images under the symmetries of the board
+    Region(u64),
+    Anywhere,
+}
+
+const SYMMETRIES: [fn(Position) -> Position; 8] = [
+    |pos| pos,
+    Position::rotate_clockwise,
+    Position::rotate_counterclockwise,
+    Position::rotate_twice,
+    Position::mirror_diagonal,
+    |pos| pos.rotate_clockwise().mirror_diagonal(),
+    |pos| pos.rotate_counterclockwise().mirror_diagonal(),
+    |pos| pos.rotate_twice().mirror_diagonal(),
+];
+
+fn map_squares(squares: u64, f: &dyn Fn(Position) -> Position) -> u64 {
+    (0..64).filter(|i| squares >> i & 1 == 1).fold(0, |result, i| result | 1 << f(Position::from_u8(i)).to_u8())
+}
+
+// every region a state can have after normalization, sorted so that it doesn't matter which of them is passed
+pub fn region_images(squares: u64) -> Vec<u64> {
+    let mut images: Vec<u64> = SYMMETRIES.iter().map(|f| map_squares(squares, f)).collect();
+    images.sort();
+    images.dedup();
+    images
+}
+
+fn squares_of(positions: &[&str]) -> u64 {
+    positions.iter().fold(0, |result, pos| result | 1 << Position::from_string(&String::from(*pos)).unwrap().to_u8())
+}
+
+// a preset or a comma separated list of squares
+pub fn parse_region(s: &str) -> Result<u64, String> {
+    let squares = match s {
+        "corners" => squares_of(&["a1", "h1", "a8", "h8"]),
+        "rim" => (0..28).fold(0, |result, i| result | 1 << Position::from_u8_rim(i).to_u8()),
+        "a-file" => 0x0101010101010101,
+        "h-file" => 0x8080808080808080,
+        "1st-rank" => 0xff,
+        "8th-rank" => 0xff << 56,
+        _ => s.split(',').map(|square| Position::from_string(&String::from(square.trim())).map(|pos| 1u64 << pos.to_u8())).collect::<Result<Vec<u64>, String>>()?.iter().fold(0, |result, square| result | square),
+    };
+    if squares == 0 {
+        return Err(String::from("A region needs at least one square!"));
+    }
+    Ok(squares)
+}
+
+impl Target {
+    pub fn contains(self, pos: Position) -> bool {
+        match self {
+            Target::Field(target) => target == pos,
+            Target::Region(squares) => squares >> pos.to_u8() & 1 == 1,
+            Target::Anywhere => true,
+        }
+    }
+
+    pub fn squares(self) -> Vec<Position> {
+        (0..64).map(Position::from_u8).filter(|pos| self.contains(*pos)).collect()
+    }
+
+    pub fn map(self, f: &dyn Fn(Position) -> Position) -> Self {
+        match self {
+            Target::Field(target) => Target::Field(f(target)),
+            Target::Region(squares) => Target::Region(map_squares(squares, f)),
+            Target::Anywhere => Target::Anywhere,
+        }
+    }
+
+    // unchanged by the reflection along the a1-h8 diagonal
+    pub fn is_symmetric(self) -> bool {
+        self.map(&Position::mirror_diagonal) == self
+    }
+
+    // the slice of the target among the targets of its tablebase, and the number of these targets
+    pub fn slice(self) -> (u8, u8) {
+        match self {
+            Target::Field(target) => (target.to_u8_target(), TARGET_COUNT as u8),
+            Target::Region(squares) => {
+                let images = region_images(squares);
+                (images.iter().position(|image| *image == squares).unwrap() as u8, images.len() as u8)
+            }
+            Target::Anywhere => (0, 1),
+        }
+    }
+}
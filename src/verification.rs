@@ -5,8 +5,7 @@ use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use chess::Board;
-use crate::search::DRAW;
-use crate::search::NOT_CALCULATED;
+use crate::search::{value_at, WIDE_DRAW, WIDE_NOT_CALCULATED};
 use chess::BoardStatus;
 use chess::Color;
 use chess::Square;
@@ -15,26 +14,45 @@ use chess::File;
 use chess::Piece;
 use chess::MoveGen;
 use indicatif::ProgressBar;
+use crate::encoding::{IndexScheme, Mode};
+use crate::target::Target;
+use crate::captures::Captures;
+use crate::material::{Material, MAX_PIECES, PIECES};
 
 impl State {
     pub fn to_board(&self) -> Board {
         Board::from_fen(self.to_fen()).expect(self.to_lichess().as_str())
     }
-    pub fn from_board(board: Board, target_field: Position) -> Self {
-        let mut knights = [Position::from_u8(0), Position::from_u8(0), Position::from_u8(0)];
-        let mut i = 0;
-        for square in board.pieces(Piece::Knight) {
-            knights[i] = Position::from_chess_square(square);
-            i += 1;
+    // The material is taken from the board, so it may have less pieces than the tablebase after a capture.
+    // Boards with pawns, black pieces besides the king or too many white pieces have no state.
+    pub fn from_board(board: Board, target: Target) -> Result<Self, String> {
+        let white = board.color_combined(Color::White);
+        let black = board.color_combined(Color::Black);
+        if (black & !board.pieces(Piece::King)).popcnt() > 0 {
+            return Err(String::from("Black can only have the king!"));
         }
-        assert_eq!(i, 3);
-        State {
+        if board.pieces(Piece::Pawn).popcnt() > 0 {
+            return Err(String::from("Positions with pawns are not supported!"));
+        }
+        if (white & !board.pieces(Piece::King)).popcnt() as usize > MAX_PIECES {
+            return Err(format!("White can only have up to {} pieces besides the king!", MAX_PIECES));
+        }
+        let mut kinds = vec![];
+        let mut pieces = [Position::from_u8(0); MAX_PIECES];
+        for (piece, kind) in PIECES.iter().zip(&[Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen]) {
+            for square in board.pieces(*kind) & white {
+                pieces[kinds.len()] = Position::from_chess_square(square);
+                kinds.push(*piece);
+            }
+        }
+        Ok(State {
             white_to_move: board.side_to_move() == Color::White,
             white_king: Position::from_chess_square(board.king_square(Color::White)),
             black_king: Position::from_chess_square(board.king_square(Color::Black)),
-            target_field,
-            knights
-        }
+            target,
+            material: Material::from_pieces(&kinds),
+            pieces,
+        })
     }
 }
 
@@ -47,31 +65,30 @@ impl Position {
     }
 }
 
-
-fn has_mate_in_one_on(board: &Board, target: &Square) -> bool {
-    MoveGen::new_legal(&board).any(|m| board.make_move_new(m).status() == BoardStatus::Checkmate && board.king_square(Color::Black) == *target)
-}
-
-fn verify_state(dp: &Vec<u8>, state: State) -> bool {
+fn verify_state(dp: &[u8], width: usize, mode: Mode, scheme: IndexScheme, captures: &Captures, state: State) -> bool {
+    // the board is built from the normalized pieces, so the target has to be normalized with them
+    let state = state.normalize();
     let board = state.to_board();
-    let packed = state.pack();
-    let dp_packed = dp[packed as usize];
+    let packed = state.pack_in(mode, scheme);
+    let dp_packed = value_at(dp, width, packed as usize);
+    // the position white wins with, the other one without moves is a draw
+    let (goal, name) = if mode.is_stalemate() { (BoardStatus::Stalemate, "stalemate") } else { (BoardStatus::Checkmate, "checkmate") };
 
-    if dp_packed == NOT_CALCULATED || dp_packed == DRAW {
-        if board.status() == BoardStatus::Checkmate && board.king_square(Color::Black) == state.target_field.to_chess_square() {
-            println!("{} ({}) marked as draw, but black king is checkmated on target field {:?}!", state.to_lichess(), state.pack(), state.target_field);
+    if dp_packed == WIDE_NOT_CALCULATED || dp_packed == WIDE_DRAW {
+        if board.status() == goal && state.black_king_on_target() {
+            println!("{} ({}) marked as draw, but black king is in {} on the target {:?}!", state.to_lichess(), state.pack_in(mode, scheme), name, state.target);
             return false;
         }
-        if board.status() == BoardStatus::Checkmate && board.king_square(Color::Black) != state.target_field.to_chess_square() {
+        if board.status() == goal && !state.black_king_on_target() {
             return true;
         }
         if state.white_to_move {
             for m in MoveGen::new_legal(&board) {
                 let new_board = board.make_move_new(m);
-                let new_state = State::from_board(new_board, state.target_field);
-                let res = dp[new_state.pack() as usize];
-                if res != NOT_CALCULATED && res != DRAW {
-                    println!("{} marked as draw, but white is to play and child state {} after move {:?} is not marked as draw (target field: {:?})!", state.to_lichess(), new_state.to_lichess(), m, state.target_field);
+                let new_state = State::from_board(new_board, state.target).unwrap();
+                let res = value_at(dp, width, new_state.pack_in(mode, scheme) as usize);
+                if res != WIDE_NOT_CALCULATED && res != WIDE_DRAW {
+                    println!("{} marked as draw, but white is to play and child state {} after move {:?} is not marked as draw (target: {:?})!", state.to_lichess(), new_state.to_lichess(), m, state.target);
                     return false;
                 }
             }
@@ -79,95 +96,88 @@ fn verify_state(dp: &Vec<u8>, state: State) -> bool {
         else {
             let mut has_draw = false;
             for m in MoveGen::new_legal(&board) {
-                let new_board = board.make_move_new(m);
-                if new_board.pieces(Piece::Knight).popcnt() < 3 {
-                    if has_mate_in_one_on(&new_board, &state.target_field.to_chess_square()) {
-                        continue;
+                let new_state = State::from_board(board.make_move_new(m), state.target).unwrap();
+                if new_state.material != state.material {
+                    if captures.get(&new_state) == WIDE_DRAW {
+                        has_draw = true;
+                        break
                     }
-                    has_draw = true;
-                    break
+                    continue;
                 }
-                let new_state = State::from_board(new_board, state.target_field);
-                let res = dp[new_state.pack() as usize];
-                if res == NOT_CALCULATED || res == DRAW {
+                let res = value_at(dp, width, new_state.pack_in(mode, scheme) as usize);
+                if res == WIDE_NOT_CALCULATED || res == WIDE_DRAW {
                     has_draw = true;
                     break
                 }
             }
-            if !has_draw && board.status() != BoardStatus::Stalemate {
-                println!("{} marked as draw, but black is to play and has no move to achieve a draw (target field: {:?})!", state.to_lichess(), state.target_field);
+            if !has_draw && board.status() == BoardStatus::Ongoing {
+                println!("{} marked as draw, but black is to play and has no move to achieve a draw (target: {:?})!", state.to_lichess(), state.target);
                 return false;
             }
         }
     }
     else if dp_packed == 0 {
         if state.white_to_move {
-            println!("{} has mate in 0 on {:?} but white is to move?", state.to_lichess(), state.target_field);
+            println!("{} has {} in 0 on {:?} but white is to move?", state.to_lichess(), name, state.target);
             return false;
         }
-        if board.status() != BoardStatus::Checkmate {
-            println!("{} marked as checkmate in 0 on {:?}, but it's not!", state.to_lichess(), state.target_field);
+        if board.status() != goal {
+            println!("{} marked as {} in 0 on {:?}, but it's not!", state.to_lichess(), name, state.target);
             return false;
         }
-        if board.king_square(Color::Black) != state.target_field.to_chess_square() {
-            println!("{} marked as checkmate in 0, but black king is not on target field {:?} ({:?})!", state.to_lichess(), state.target_field.to_chess_square(), state.target_field);
+        if !state.black_king_on_target() {
+            println!("{} marked as {} in 0, but black king is not on the target {:?}!", state.to_lichess(), name, state.target);
             return false;
         }
     }
     else {
         if state.white_to_move {
             if dp_packed % 2 == 0 {
-                println!("{} has mate in {} halfmoves on {:?}, but white is to move!", state.to_lichess(), dp_packed, state.target_field);
+                println!("{} has mate in {} halfmoves on {:?}, but white is to move!", state.to_lichess(), dp_packed, state.target);
                 return false;
             }
             if board.status() != BoardStatus::Ongoing {
-                println!("{} is marked as mate in {} halfmoves on {:?}, but there are no moves that can be played!", state.to_lichess(), dp_packed, state.target_field);
+                println!("{} is marked as mate in {} halfmoves on {:?}, but there are no moves that can be played!", state.to_lichess(), dp_packed, state.target);
                 return false;
             }
-            let mut min = 254;
+            let mut min = WIDE_NOT_CALCULATED;
             for m in MoveGen::new_legal(&board) {
                 let new_board = board.make_move_new(m);
-                let new_state = State::from_board(new_board, state.target_field);
-                let res = dp[new_state.pack() as usize];
+                let new_state = State::from_board(new_board, state.target).unwrap();
+                let res = value_at(dp, width, new_state.pack_in(mode, scheme) as usize);
                 if res < min {
                     min = res;
                 }
             }
             if min != dp_packed - 1 {
-                println!("{} ({}) is marked as mate in {} halfmoves on {:?}, but the minimum that can be achieved is {} + 1!", state.to_lichess(), packed, dp_packed, state.target_field, min);
+                println!("{} ({}) is marked as mate in {} halfmoves on {:?}, but the minimum that can be achieved is {} + 1!", state.to_lichess(), packed, dp_packed, state.target, min);
                 return false;
             }
         }
         else {
             if dp_packed % 2 == 1 {
-                println!("{} has mate in {} halfmoves on {:?}, but black is to move!", state.to_lichess(), dp_packed, state.target_field);
+                println!("{} has mate in {} halfmoves on {:?}, but black is to move!", state.to_lichess(), dp_packed, state.target);
                 return false;
             }
             if board.status() != BoardStatus::Ongoing {
-                println!("{} is marked as mate in {} halfmoves on {:?}, but there are no moves that can be played!", state.to_lichess(), dp_packed, state.target_field);
+                println!("{} is marked as mate in {} halfmoves on {:?}, but there are no moves that can be played!", state.to_lichess(), dp_packed, state.target);
                 return false;
             }
             let mut max = 0;
             for m in MoveGen::new_legal(&board) {
-                let new_board = board.make_move_new(m);
-                let res = if new_board.pieces(Piece::Knight).popcnt() < 3 {
-                    if has_mate_in_one_on(&new_board, &state.target_field.to_chess_square()) {
-                        1
-                    }
-                    else {
-                        255
-                    }
+                let new_state = State::from_board(board.make_move_new(m), state.target).unwrap();
+                let res = if new_state.material != state.material {
+                    captures.get(&new_state)
                 }
                 else {
-                    let new_state = State::from_board(new_board, state.target_field);
-                    dp[new_state.pack() as usize]
+                    value_at(dp, width, new_state.pack_in(mode, scheme) as usize)
                 };
                 if res > max {
                     max = res;
                 }
             }
             if max != dp_packed - 1 {
-                println!("{} is marked as mate in {} halfmoves on {:?}, but the maximum that can be achieved is {} + 1 (a value of 254 or 255 indicates a draw)!", state.to_lichess(), dp_packed, state.target_field, max);
+                println!("{} is marked as mate in {} halfmoves on {:?}, but the maximum that can be achieved is {} + 1 (a value of 65534 or 65535 indicates a draw)!", state.to_lichess(), dp_packed, state.target, max);
                 return false;
             }
         }
@@ -175,70 +185,94 @@ fn verify_state(dp: &Vec<u8>, state: State) -> bool {
     return true;
 }
 
-pub fn verify(dp: &Vec<u8>, pool: ThreadPool) -> bool {
+// In a helpmate both sides play towards the mate, so a position is one halfmove longer than its shortest move.
+fn verify_helpmate_state(dp: &[u8], width: usize, mode: Mode, scheme: IndexScheme, captures: &Captures, state: State) -> bool {
+    let state = state.normalize();
+    let board = state.to_board();
+    let dp_packed = match value_at(dp, width, state.pack_in(mode, scheme) as usize) {
+        WIDE_NOT_CALCULATED => WIDE_DRAW,
+        value => value,
+    };
+    let shortest = if board.status() == BoardStatus::Checkmate && state.black_king_on_target() {
+        0
+    }
+    else {
+        MoveGen::new_legal(&board)
+            .map(|m| {
+                let new_state = State::from_board(board.make_move_new(m), state.target).unwrap();
+                if new_state.material != state.material {
+                    captures.get(&new_state)
+                }
+                else {
+                    value_at(dp, width, new_state.pack_in(mode, scheme) as usize)
+                }
+            })
+            .filter(|value| *value != WIDE_NOT_CALCULATED && *value != WIDE_DRAW)
+            .map(|value| value + 1)
+            .min()
+            .unwrap_or(WIDE_DRAW)
+    };
+    if dp_packed != shortest {
+        println!("{} is marked as helpmate in {} halfmoves on {:?}, but the shortest one takes {} (a value of 65535 indicates a draw)!", state.to_lichess(), dp_packed, state.target, shortest);
+        return false;
+    }
+    true
+}
+
+pub fn verify(dp: &[u8], width: usize, material: Material, mode: Mode, scheme: IndexScheme, captures: &Captures, pool: ThreadPool) -> bool {
     println!("Verifying tablebase...");
+    let targets: Vec<Target> = (0..mode.target_count()).map(|index| mode.target(index)).collect();
+    let verify_state = if mode.is_helpmate() { verify_helpmate_state } else { verify_state };
     let result = AtomicBool::new(true);
-    let bar = ProgressBar::new(16 * 63 * 28 * 37820);
+    let state_count = mode.state_count(material);
+    let bar = ProgressBar::new(state_count as u64);
     pool.install(|| {
         let result = &result;
         let bar = &bar;
         let counter = AtomicUsize::new(0);
+        let counter = &counter;
         pool.scope(|s| {
-            for target_field in 0..28 {
-                let result = &result;
-                let counter = &counter;
-                let bar = &bar;
-                s.spawn(move |_| {
-                    for white_king in 0..16 {
+            for target in &targets {
+                for white_king in 0..10 {
+                    s.spawn(move |_| {
                         for black_king in 0..64 {
-                            let white_king_pos = Position::from_u8_bottom_left(white_king);
+                            let white_king_pos = Position::from_u8_triangle(white_king);
                             let black_king_pos = Position::from_u8(black_king);
                             if white_king_pos.king_moves().contains(black_king_pos) || white_king_pos == black_king_pos {
                                 continue;
                             }
-                            for knight3 in 0..64 {
-                                if knight3 == white_king_pos.to_u8() || knight3 == black_king {
-                                    continue;
+                            let free = !(1u64 << white_king_pos.to_u8()) & !(1u64 << black_king);
+                            for pieces in material.placements(free) {
+                                let state = State {
+                                    white_king: white_king_pos,
+                                    material,
+                                    pieces,
+                                    black_king: black_king_pos,
+                                    target: *target,
+                                    white_to_move: false,
+                                };
+                                if !result.load(Ordering::SeqCst) {
+                                    return;
+                                }
+                                if !verify_state(dp, width, mode, scheme, captures, state) {
+                                    result.store(false, Ordering::SeqCst);
+                                    return;
                                 }
-                                for knight2 in 0..knight3 {
-                                    if knight2 == white_king_pos.to_u8() || knight2 == black_king {
-                                        continue;
-                                    }
-                                    for knight1 in 0..knight2 {
-                                        if knight1 == white_king_pos.to_u8() || knight1 == black_king {
-                                            continue;
-                                        }
-                                        let state = State {
-                                            white_king: white_king_pos,
-                                            black_king: black_king_pos,
-                                            knights: [Position::from_u8(knight1), Position::from_u8(knight2), Position::from_u8(knight3)],
-                                            target_field: Position::from_u8_rim(target_field),
-                                            white_to_move: false,
-                                        };
-                                        if !result.load(Ordering::SeqCst) {
-                                            return;
-                                        }
-                                        if !verify_state(dp, state) {
-                                            result.store(false, Ordering::SeqCst);
-                                            return;
-                                        }
 
-                                        let state = State { white_to_move: true, ..state };
-                                        if !state.covered_by_white().contains(black_king_pos) && !verify_state(dp, state)  {
-                                            result.store(false, Ordering::SeqCst);
-                                            return;
-                                        }
-                                        let val = counter.fetch_add(2, Ordering::SeqCst) + 2;
-                                        if val % (16 * 63 * 28 * 2 * 37820 / 5000) < 2 {
-                                            bar.set_position(val as u64);
-                                            // println!("Verified {} / {} = {}% of states", val, 16 * 63 * 28 * 2 * 37820, val as f64 * 100.0 / (16.0 * 63.0 * 28.0 * 2.0 * 37820.0));
-                                        }
-                                    }
+                                let state = State { white_to_move: true, ..state };
+                                if !state.covered_by_white().contains(black_king_pos) && !verify_state(dp, width, mode, scheme, captures, state)  {
+                                    result.store(false, Ordering::SeqCst);
+                                    return;
+                                }
+                                let val = counter.fetch_add(2, Ordering::SeqCst) + 2;
+                                if val % (state_count / 5000).max(2) < 2 {
+                                    bar.set_position(val as u64);
+                                    // println!("Verified {} / {} = {}% of states", val, state_count, val as f64 * 100.0 / state_count as f64);
                                 }
                             }
                         }
-                    }
-                });
+                    });
+                }
             }
         });
         bar.finish();
@@ -0,0 +1,23 @@
+use rayon::prelude::*;
+use crate::search::{value_at, WIDE_DRAW, WIDE_NOT_CALCULATED};
+use crate::tablebase::TableData;
+
+// bit i % 8 of byte i / 8 is set if white can force mate in state i
+pub fn to_bitmap(dp: &[u8], width: usize) -> Vec<u8> {
+    dp.par_chunks(8 * width)
+        .map(|chunk| {
+            let mut byte = 0u8;
+            for i in 0..chunk.len() / width {
+                let value = value_at(chunk, width, i);
+                if value != WIDE_DRAW && value != WIDE_NOT_CALCULATED {
+                    byte |= 1 << i;
+                }
+            }
+            byte
+        })
+        .collect()
+}
+
+pub fn is_win(bitmap: &TableData, index: usize) -> bool {
+    (bitmap.get(index / 8) >> (index % 8)) & 1 == 1
+}
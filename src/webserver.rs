@@ -1,11 +1,11 @@
 use rocket::fairing::AdHoc;
 use rocket_contrib::templates::Template;
-use crate::tablebase::{Tablebase, Evaluation};
+use crate::tablebase::{Tablebase, Evaluation, halfmove_clock};
 use rocket_contrib::json::{JsonValue, Json};
 use std::collections::HashMap;
 use crate::state::Position;
 use rocket::State;
-use crate::tablebase::Value::MateIn;
+use crate::tablebase::Value::{MateIn, Win, Draw, Unknown};
 use crate::state;
 use serde::{Deserialize, Serialize};
 use rocket::response::NamedFile;
@@ -21,6 +21,12 @@ struct EvalParam {
 #[derive(Serialize, Deserialize, Clone)]
 struct EvalResponse {
     mate_in: isize,
+    // set when white can force mate, mate_in is -1 if the tablebase doesn't know the distance
+    win: bool,
+    // set when the tablebase was generated with --max-depth and doesn't know the result yet
+    unknown: bool,
+    // in rule-50 mode, whether the mate surely comes before the fifty-move rule draws the game, false if that isn't guaranteed
+    in_time: Option<bool>,
     best_moves: Vec<[String; 2]>
 }
 
@@ -36,32 +42,61 @@ fn assets(asset: PathBuf) -> Option<NamedFile> {
 }
 
 
+struct Settings {
+    // the threads used to generate missing target fields
+    threads: usize,
+    // the halfmove clock of the FEN is passed to the evaluation
+    rule50: bool,
+}
+
 #[post("/eval", format="application/json", data="<arg>")]
-fn eval(arg: Json<EvalParam>, tb: State<Tablebase>) -> Result<Json<EvalResponse>, u32> {
+fn eval(arg: Json<EvalParam>, tb: State<Tablebase>, settings: State<Settings>) -> Result<Json<EvalResponse>, u32> {
     let param = arg.into_inner().clone();
-    let target = Position::from_string(&param.target).unwrap();
-    let r = chess::Board::from_fen(param.fen)
-        .and_then(|state| if state.is_sane() { Some(state) } else { None })
-        .map(|state| tb.eval(state, target))
-        .map(|evaluation| {
-            let mate_in = match evaluation.value {
-                MateIn(i) => i as isize,
-                Draw => -1
-            };
-            let best_moves = evaluation.best_moves.iter().map(|m| [m.get_source().to_string(), m.get_dest().to_string()]).collect();
-            Json(EvalResponse {mate_in, best_moves})
-        });
-    if r.is_none() {
-        Err(400)
+    // the target is ignored if the tablebase has no target fields
+    let target = if tb.mode().has_fields() {
+        let target = Position::from_string(&param.target).map_err(|_| 400u32)?;
+        // a partitioned tablebase generates a missing target on the first request and keeps its slices in the directory
+        if !tb.has_target(target) {
+            if let Err(e) = tb.generate_target(target, settings.threads) {
+                eprintln!("{}", e);
+                return Err(500);
+            }
+        }
+        Some(target)
     }
     else {
-        Ok(r.unwrap())
+        None
+    };
+    let clock = if settings.rule50 {
+        Some(halfmove_clock(&param.fen).map_err(|_| 400u32)?)
     }
+    else {
+        None
+    };
+    let board = chess::Board::from_fen(param.fen)
+        .and_then(|state| if state.is_sane() { Some(state) } else { None })
+        .ok_or(400u32)?;
+    // positions the tablebase doesn't have, like other material, are bad requests
+    tb.state_of(board, target).map_err(|_| 400u32)?;
+    // the tablebase has the position, so the evaluation only fails if a slice or a table after a capture can't be read
+    let evaluation = tb.eval(board, target, clock).map_err(|e| {
+        eprintln!("{}", e);
+        500u32
+    })?;
+    let (mate_in, win, unknown) = match evaluation.value {
+        MateIn(i) => (i as isize, true, false),
+        Win => (-1, true, false),
+        Draw => (-1, false, false),
+        Unknown => (-1, false, true),
+    };
+    let best_moves = evaluation.best_moves.iter().map(|m| [m.get_source().to_string(), m.get_dest().to_string()]).collect();
+    Ok(Json(EvalResponse {mate_in, win, unknown, in_time: evaluation.in_time, best_moves}))
 }
 
-pub fn start_server(tb: Tablebase) {
+pub fn start_server(tb: Tablebase, threads: usize, rule50: bool) {
     rocket::ignite()
         .manage(tb)
+        .manage(Settings { threads, rule50 })
         .mount("/", routes![index, eval, assets])
         .attach(Template::fairing())
         .launch();